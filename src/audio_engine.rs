@@ -1,80 +1,659 @@
-use crate::vban::transmitter::USABLE_DATA_PACKET_SIZE;
-use futures::future::FutureExt;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, OnceLock};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use crate::config::global::{PulseConfig, PulseStreamConfig, StreamFadeConfig};
+use crate::dsp::echo::EchoReference;
+use crate::dsp::fade::StreamFade;
+use crate::dsp::mix::SourceMixer;
+use crate::dsp::sidetone::Sidetone;
+use crate::dsp::stream_mix::StreamMixer;
+use crate::dsp::Pipeline;
+use crate::pcm::{f32_to_s24le, s24le_levels};
+use crate::stats::{PlaybackStats, Stats, StreamStats};
+use crate::task::AbortOnDrop;
+use futures::future::{self, FutureExt, OptionFuture};
 use futures::select;
+use futures::stream::{FuturesUnordered, StreamExt};
 use libpulse_binding::def::BufferAttr;
 use libpulse_binding::error::PAErr;
 use libpulse_binding::sample::{Format, Spec};
 use libpulse_binding::stream::Direction;
+use libpulse_binding::time::MicroSeconds;
 use libpulse_simple_binding::Simple;
-use once_cell::sync::Lazy;
-
-static SPEC: Lazy<Spec> = Lazy::new(|| {
-    let spec = Spec {
-        format: Format::S24le,
-        channels: 2,
-        rate: 48000,
-    };
-    assert!(spec.is_valid());
-    spec
-});
-
-pub async fn run(
-    mut pa_recv: tokio::sync::mpsc::Receiver<Vec<u8>>,
-    pa_send: tokio::sync::mpsc::Sender<Vec<u8>>,
-) -> Result<(), PAErr> {
-    let mut output_task = tokio::spawn(async move {
-        let s = Simple::new(
-            None,                // Use the default server
-            "Audio Bicycle",     // Our application’s name
-            Direction::Playback, // We want a playback stream
-            None,                // Use the default device
-            "VBAN Output",       // Description of our stream
-            &SPEC,               // Our sample format
-            None,                // Use default channel map
-            None,                // Use default buffering attributes
+
+/// The rate and channel count of some 24-bit audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub rate: u32,
+    pub channels: u8,
+}
+
+impl AudioFormat {
+    pub fn spec(&self) -> Spec {
+        Spec {
+            format: Format::S24le,
+            channels: self.channels,
+            rate: self.rate,
+        }
+    }
+}
+
+impl Display for AudioFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} Hz, {} channels", self.rate, self.channels)
+    }
+}
+
+/// What passes between the audio engine and the network.
+pub enum Audio {
+    Samples(Vec<u8>),
+    /// The sender's noise gate shut, so nothing more will arrive for a while. This is silence, not loss.
+    Idle,
+    /// The samples that follow are in this format. Only received audio changes format.
+    Format(AudioFormat),
+}
+
+/// How much silence to write at a time while the peer is away.
+const SILENCE_CHUNK: Duration = Duration::from_millis(10);
+/// How much silence to keep queued while the peer is away.
+const SILENCE_QUEUED: MicroSeconds = MicroSeconds(30_000);
+
+/// How many packets can wait in a source that isn't setting the pace, before one is skipped to catch up.
+const MAX_WAITING_PACKETS: usize = 3;
+
+/// Leaves every buffering attribute up to PulseAudio.
+const DEFAULT_BUFFER: BufferAttr = BufferAttr {
+    maxlength: u32::MAX,
+    tlength: u32::MAX,
+    prebuf: u32::MAX,
+    minreq: u32::MAX,
+    fragsize: u32::MAX,
+};
+
+/// libpulse reads stream properties from variables like this, and the simple API has no other way to set them.
+pub const MEDIA_ROLE_VAR: &str = "PULSE_PROP_media.role";
+
+/// The media role set at startup, if any.
+static MEDIA_ROLE: OnceLock<Option<String>> = OnceLock::new();
+
+/// Gives our streams `role` for the life of the process. The environment can only be changed safely while there's
+/// one thread, so this has to be called before the runtime starts.
+pub fn set_media_role(role: Option<String>) {
+    if let Some(role) = &role {
+        std::env::set_var(MEDIA_ROLE_VAR, role);
+    }
+    MEDIA_ROLE
+        .set(role)
+        .expect("media role should only be set once");
+}
+
+/// Whether our streams were given a media role.
+pub fn has_media_role() -> bool {
+    MEDIA_ROLE.get().is_some_and(Option::is_some)
+}
+
+/// Plays received audio and captures audio to send, through PulseAudio.
+pub struct AudioEngine {
+    /// The format to capture in. Received audio is expected in this too, until it says otherwise.
+    pub format: AudioFormat,
+    /// How many bytes to capture at a time.
+    pub packet_size: u32,
+    pub stats: Arc<Stats>,
+    pub capture: Pipeline,
+    /// Builds the playback processing for received audio, again whenever its format changes.
+    pub playback: Arc<dyn Fn(AudioFormat) -> Pipeline + Send + Sync>,
+    /// Where to record what's played, if echo cancellation is on.
+    pub echo_reference: Option<Arc<EchoReference>>,
+    /// Combines several sources into what's captured, if set. Otherwise only the capture device is used.
+    pub mixer: Option<SourceMixer>,
+    /// Plays captured audio back locally, if set.
+    pub sidetone: Option<Sidetone>,
+    /// How received audio starts and stops.
+    pub stream_fade: StreamFadeConfig,
+    pub pulse: PulseConfig,
+}
+
+impl AudioEngine {
+    pub async fn run(
+        self,
+        outputs: Vec<PlaybackOutput>,
+        pa_send: tokio::sync::mpsc::Sender<Audio>,
+    ) -> Result<(), PAErr> {
+        let AudioEngine {
+            format: capture_format,
+            packet_size,
+            stats,
+            mut capture,
+            playback: new_playback,
+            echo_reference,
+            mut mixer,
+            sidetone,
+            stream_fade,
+            pulse,
+        } = self;
+        if MEDIA_ROLE
+            .get()
+            .is_some_and(|role| *role != pulse.media_role)
+        {
+            log::warn!("The new media_role applies once the service is started again");
+        }
+        let sidetone_pulse = pulse.clone();
+        let mut output_tasks: FuturesUnordered<_> = outputs
+            .into_iter()
+            .map(|output| {
+                let mut player = Player {
+                    format: capture_format,
+                    pulse: pulse.clone(),
+                    stream_fade: stream_fade.clone(),
+                    new_playback: Arc::clone(&new_playback),
+                    echo_reference: None,
+                    stats: Arc::clone(&stats),
+                    stream_stats: None,
+                };
+                match output.sink() {
+                    Some((sink, stream_stats)) => {
+                        player.pulse.playback.device = Some(sink.clone());
+                        player.stream_stats = Some(Arc::clone(stream_stats));
+                    }
+                    // Only what's played on the playback device can be picked up by the mic.
+                    None => player.echo_reference = echo_reference.clone(),
+                }
+                match output {
+                    PlaybackOutput::Direct { audio } => {
+                        AbortOnDrop::spawn(player.play_direct(audio))
+                    }
+                    PlaybackOutput::Mixed { mixer, audio, .. } => {
+                        AbortOnDrop::spawn(player.play_mixed(mixer, audio))
+                    }
+                }
+            })
+            .collect();
+        // Sidetone gets its own stream with a small buffer, so it's heard quickly and keeps going without the network.
+        let (sidetone_send, sidetone_task) = match sidetone {
+            Some(mut sidetone) => {
+                let (send, mut recv) = tokio::sync::mpsc::channel::<Vec<u8>>(4);
+                let task = AbortOnDrop::spawn(async move {
+                    let stream = PulseStreamConfig {
+                        device: sidetone_pulse.playback.device.clone(),
+                        ..Default::default()
+                    };
+                    let s = open(
+                        &sidetone_pulse,
+                        Direction::Playback,
+                        &stream,
+                        "Sidetone",
+                        &capture_format.spec(),
+                        BufferAttr {
+                            tlength: packet_size * 2,
+                            ..DEFAULT_BUFFER
+                        },
+                    )?;
+                    while let Some(buffer) = recv.recv().await {
+                        let buffer = sidetone.process(&buffer);
+                        tokio::task::block_in_place(|| s.write(&buffer))?;
+                    }
+                    Ok::<_, PAErr>(())
+                });
+                (Some(send), Some(task))
+            }
+            None => (None, None),
+        };
+        let mut sidetone_task = OptionFuture::from(sidetone_task.map(FutureExt::fuse));
+        let mut input_task = AbortOnDrop::spawn(async move {
+            let frames = packet_size / (u32::from(capture_format.channels) * 3);
+            // Without a mix, there's just the capture device.
+            let sources = match &mixer {
+                Some(mixer) => mixer
+                    .sources()
+                    .iter()
+                    .map(|source| (source.device.clone(), source.channels))
+                    .collect(),
+                None => vec![(None, capture_format.channels)],
+            };
+            let mut inputs = Vec::new();
+            for (device, channels) in sources {
+                let format = AudioFormat {
+                    rate: capture_format.rate,
+                    channels,
+                };
+                let size = frames * u32::from(channels) * 3;
+                inputs.push(Input::open(&pulse, device, format, size)?);
+            }
+
+            let mut buffer = vec![0u8; packet_size as usize];
+            let mut idle = false;
+            loop {
+                let (first, others) = inputs.split_first_mut().expect("there's always a source");
+                let latency = first.s.get_latency()?;
+                // The oldest audio waiting was captured this long ago, and that's what we read first.
+                let captured_at = Instant::now() - Duration::from(latency);
+                stats.capture_latency.set(latency.as_secs_f64());
+                // PulseAudio drops captured audio once its buffer is full.
+                if latency >= first.max_latency {
+                    stats.overruns.inc();
+                }
+                tokio::task::block_in_place(|| first.s.read(&mut first.buffer))?;
+                // The first source sets the pace. The others may run on other clocks, or stop while their sink is
+                // suspended, so take what they have without waiting.
+                for input in others {
+                    input.read_available()?;
+                }
+                match &mut mixer {
+                    Some(mixer) => {
+                        let buffers: Vec<&[u8]> =
+                            inputs.iter().map(|input| &input.buffer[..]).collect();
+                        mixer.mix(&buffers, &mut buffer);
+                    }
+                    None => buffer.copy_from_slice(&inputs[0].buffer),
+                }
+                if let Some(sidetone) = &sidetone_send {
+                    // Better to skip some than to hold up capture.
+                    let _ = sidetone.try_send(buffer.clone());
+                }
+                let open = capture.process(&mut buffer, captured_at);
+                if let Some(agc_gain) = capture.agc_gain_db() {
+                    stats.capture_agc_gain.set(f64::from(agc_gain));
+                }
+                if let Some(reduction) = capture.echo_reduction_db() {
+                    stats.capture_echo_reduction.set(f64::from(reduction));
+                }
+                let (peak, rms) = s24le_levels(&buffer);
+                stats.capture_peak.set(f64::from(peak));
+                stats.capture_rms.set(f64::from(rms));
+                let audio = if open {
+                    idle = false;
+                    Audio::Samples(buffer.clone())
+                } else if !idle {
+                    log::debug!("Noise gate shut, pausing transmission");
+                    idle = true;
+                    Audio::Idle
+                } else {
+                    continue;
+                };
+                if (pa_send.send(audio).await).is_err() {
+                    break;
+                }
+            }
+            Ok::<_, PAErr>(())
+        })
+        .fuse();
+
+        loop {
+            (select! {
+                output_result = output_tasks.select_next_some() => output_result,
+                input_result = input_task => input_result,
+                sidetone_result = sidetone_task => sidetone_result.unwrap_or(Ok(Ok(()))),
+                complete => break,
+            })
+            .expect("task panicked")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Where received audio is played.
+pub enum PlaybackOutput {
+    /// One stream on the playback device, played as it arrives and following its format.
+    Direct {
+        audio: tokio::sync::mpsc::Receiver<Audio>,
+    },
+    /// Streams through jitter buffers, mixed together on the playback device or a sink of their own, in the order the
+    /// mixer has them.
+    Mixed {
+        /// A sink of its own, and the stats of the one stream played there.
+        sink: Option<(String, Arc<StreamStats>)>,
+        mixer: StreamMixer,
+        audio: Vec<tokio::sync::mpsc::Receiver<Audio>>,
+    },
+}
+
+impl PlaybackOutput {
+    fn sink(&self) -> Option<&(String, Arc<StreamStats>)> {
+        match self {
+            PlaybackOutput::Direct { .. } => None,
+            PlaybackOutput::Mixed { sink, .. } => sink.as_ref(),
+        }
+    }
+}
+
+/// Plays received audio on one playback stream.
+struct Player {
+    /// What to expect until the audio says otherwise.
+    format: AudioFormat,
+    pulse: PulseConfig,
+    stream_fade: StreamFadeConfig,
+    new_playback: Arc<dyn Fn(AudioFormat) -> Pipeline + Send + Sync>,
+    echo_reference: Option<Arc<EchoReference>>,
+    stats: Arc<Stats>,
+    /// The stream played here, if it's on a sink of its own.
+    stream_stats: Option<Arc<StreamStats>>,
+}
+
+impl Player {
+    /// Where this player's latency and levels are reported.
+    fn playback_stats(&self) -> &PlaybackStats {
+        match &self.stream_stats {
+            Some(stream_stats) => &stream_stats.playback,
+            None => &self.stats.playback,
+        }
+    }
+
+    /// Plays a stream as it arrives, following its format.
+    async fn play_direct(
+        self,
+        mut pa_recv: tokio::sync::mpsc::Receiver<Audio>,
+    ) -> Result<(), PAErr> {
+        let timeout = Duration::from_millis(self.stream_fade.timeout_ms);
+        let mut format = self.format;
+        let mut output = Output::open(
+            format,
+            &self.pulse,
+            &self.stream_fade,
+            &*self.new_playback,
+            DEFAULT_BUFFER,
         )?;
+        loop {
+            let Output {
+                s,
+                playback,
+                fade,
+                silence,
+            } = &mut output;
+            // While the peer is away, wake up often enough to keep silence queued.
+            let wait = if fade.is_stopped() {
+                SILENCE_CHUNK
+            } else {
+                timeout
+            };
+            let audio = match tokio::time::timeout(wait, pa_recv.recv()).await {
+                Ok(Some(audio)) => Some(audio),
+                Ok(None) => break,
+                Err(_) => None,
+            };
+            let buffer = match audio {
+                Some(Audio::Samples(mut buffer)) => {
+                    let latency = s.get_latency()?;
+                    self.playback_stats().latency.set(latency.as_secs_f64());
+                    self.stats
+                        .playback_latency_histogram
+                        .observe(latency.as_secs_f64());
+                    // PulseAudio ran out of audio to play before this arrived.
+                    if !fade.is_stopped() && latency.0 == 0 {
+                        self.stats.underruns.inc();
+                    }
+                    if fade.is_stopped() {
+                        log::debug!("Received audio started");
+                    }
+                    playback.process(&mut buffer, Instant::now() + Duration::from(latency));
+                    let (peak, rms) = s24le_levels(&buffer);
+                    self.playback_stats().peak.set(f64::from(peak));
+                    self.playback_stats().rms.set(f64::from(rms));
+                    fade.next(&buffer)
+                }
+                Some(Audio::Format(new_format)) if new_format != format => {
+                    log::info!(
+                        "Received audio changed from {} to {}, reopening playback",
+                        format,
+                        new_format
+                    );
+                    play(s, self.echo_reference.as_deref(), format, &fade.stop())?;
+                    tokio::task::block_in_place(|| s.drain())?;
+                    format = new_format;
+                    if self
+                        .echo_reference
+                        .as_ref()
+                        .is_some_and(|r| r.sample_rate() != format.rate)
+                    {
+                        log::warn!(
+                            "Echo cancellation is off until received audio is back to {} Hz",
+                            self.format.rate
+                        );
+                    }
+                    output = Output::open(
+                        format,
+                        &self.pulse,
+                        &self.stream_fade,
+                        &*self.new_playback,
+                        DEFAULT_BUFFER,
+                    )?;
+                    continue;
+                }
+                Some(Audio::Format(_)) => continue,
+                // The sender's gate shut, or it went away.
+                Some(Audio::Idle) | None if !fade.is_stopped() => {
+                    log::debug!("Received audio stopped");
+                    self.playback_stats().peak.set(0.0);
+                    self.playback_stats().rms.set(0.0);
+                    fade.stop()
+                }
+                // Fill in with silence rather than letting PulseAudio run dry.
+                _ if s.get_latency()? < SILENCE_QUEUED => silence.clone(),
+                _ => continue,
+            };
+            play(s, self.echo_reference.as_deref(), format, &buffer)?;
+        }
+        play(
+            &output.s,
+            self.echo_reference.as_deref(),
+            format,
+            &output.fade.stop(),
+        )?;
+        Ok(())
+    }
 
-        while let Some(buffer) = pa_recv.recv().await {
-            tokio::task::block_in_place(|| s.write(&buffer))?;
+    /// Mixes streams together, keeping PulseAudio topped up from their jitter buffers.
+    async fn play_mixed(
+        self,
+        mut mixer: StreamMixer,
+        mut audio: Vec<tokio::sync::mpsc::Receiver<Audio>>,
+    ) -> Result<(), PAErr> {
+        let spec = self.format.spec();
+        let chunk = spec.usec_to_bytes(MicroSeconds(SILENCE_CHUNK.as_micros() as u64)) as u32;
+        // The mixer holds the jitter buffers, so PulseAudio only needs what's kept queued, and has to start playing
+        // before that's reached. A configured prebuf is honoured by queueing more.
+        let queued = match self.pulse.playback.buffer.prebuf {
+            Some(prebuf) => SILENCE_QUEUED.max(spec.bytes_to_usec(u64::from(prebuf))),
+            None => SILENCE_QUEUED,
+        };
+        let output = Output::open(
+            self.format,
+            &self.pulse,
+            &self.stream_fade,
+            &*self.new_playback,
+            BufferAttr {
+                tlength: spec.usec_to_bytes(queued) as u32 + chunk * 2,
+                prebuf: chunk,
+                ..DEFAULT_BUFFER
+            },
+        )?;
+        let Output {
+            s, mut playback, ..
+        } = output;
+        let chunk_frames = (self.format.rate as usize * SILENCE_CHUNK.as_millis() as usize) / 1000;
+        let mut samples = Vec::new();
+        let mut buffer = Vec::new();
+        loop {
+            let next = future::poll_fn(|cx| {
+                for (index, audio) in audio.iter_mut().enumerate() {
+                    if let Poll::Ready(received) = audio.poll_recv(cx) {
+                        return Poll::Ready(received.map(|received| (index, received)));
+                    }
+                }
+                Poll::Pending
+            });
+            match tokio::time::timeout(SILENCE_CHUNK, next).await {
+                Ok(Some((index, received))) => mixer.push(index, received),
+                Ok(None) => break,
+                Err(_) => {}
+            }
+            loop {
+                let latency = s.get_latency()?;
+                if latency >= queued {
+                    break;
+                }
+                self.playback_stats().latency.set(latency.as_secs_f64());
+                self.stats
+                    .playback_latency_histogram
+                    .observe(latency.as_secs_f64());
+                let underruns = mixer.next(chunk_frames, &mut samples);
+                self.stats.underruns.add(u64::from(underruns));
+                buffer.clear();
+                f32_to_s24le(&samples, &mut buffer);
+                playback.process(&mut buffer, Instant::now() + Duration::from(latency));
+                let (peak, rms) = s24le_levels(&buffer);
+                self.playback_stats().peak.set(f64::from(peak));
+                self.playback_stats().rms.set(f64::from(rms));
+                play(&s, self.echo_reference.as_deref(), self.format, &buffer)?;
+            }
         }
-        Ok::<_, PAErr>(())
-    })
-    .fuse();
-    let mut input_task = tokio::spawn(async move {
-        let s = Simple::new(
-            None,
-            "Audio Bicycle",
+        Ok(())
+    }
+}
+
+/// A record stream for one of the sources being captured.
+struct Input {
+    s: Simple,
+    spec: Spec,
+    /// What was last read. This is always a packet long.
+    buffer: Vec<u8>,
+    /// How far behind capture can get before PulseAudio starts dropping audio.
+    max_latency: MicroSeconds,
+}
+
+impl Input {
+    /// Opens a source, or the capture device if `device` isn't set, to read `size` bytes at a time.
+    fn open(
+        pulse: &PulseConfig,
+        device: Option<String>,
+        format: AudioFormat,
+        size: u32,
+    ) -> Result<Self, PAErr> {
+        let spec = format.spec();
+        let stream = PulseStreamConfig {
+            device: device.or_else(|| pulse.capture.device.clone()),
+            ..pulse.capture.clone()
+        };
+        let default_buffer = BufferAttr {
+            maxlength: (size * 4),
+            fragsize: (size),
+            ..Default::default()
+        };
+        let s = open(
+            pulse,
             Direction::Record,
-            None,
+            &stream,
             "VBAN Input",
-            &SPEC,
-            None,
-            Some(&BufferAttr {
-                maxlength: (USABLE_DATA_PACKET_SIZE * 4),
-                fragsize: (USABLE_DATA_PACKET_SIZE),
-                ..Default::default()
-            }),
+            &spec,
+            default_buffer,
         )?;
+        let maxlength = stream.buffer.maxlength.unwrap_or(default_buffer.maxlength);
+        Ok(Self {
+            s,
+            spec,
+            buffer: vec![0u8; size as usize],
+            max_latency: spec.bytes_to_usec(u64::from(maxlength)),
+        })
+    }
 
-        let mut buffer = vec![0u8; USABLE_DATA_PACKET_SIZE as usize];
-        loop {
-            tokio::task::block_in_place(|| s.read(&mut buffer))?;
-            if (pa_send.send(buffer.clone()).await).is_err() {
-                break;
-            }
+    /// Reads a packet if one is waiting, or leaves silence if not. If too much is waiting, some is skipped.
+    fn read_available(&mut self) -> Result<(), PAErr> {
+        let size = self.buffer.len();
+        let waiting = self.spec.usec_to_bytes(self.s.get_latency()?);
+        if waiting < size {
+            self.buffer.fill(0);
+            return Ok(());
+        }
+        if waiting >= size * MAX_WAITING_PACKETS {
+            tokio::task::block_in_place(|| self.s.read(&mut self.buffer))?;
         }
-        Ok::<_, PAErr>(())
-    })
-    .fuse();
-
-    loop {
-        (select! {
-            output_result = output_task => output_result,
-            input_result = input_task => input_result,
-            complete => break,
+        tokio::task::block_in_place(|| self.s.read(&mut self.buffer))
+    }
+}
+
+/// The playback stream for received audio, and what goes with its format.
+struct Output {
+    s: Simple,
+    playback: Pipeline,
+    fade: StreamFade,
+    /// A chunk of silence, to fill in while the peer is away.
+    silence: Vec<u8>,
+}
+
+impl Output {
+    fn open(
+        format: AudioFormat,
+        pulse: &PulseConfig,
+        stream_fade: &StreamFadeConfig,
+        new_playback: &dyn Fn(AudioFormat) -> Pipeline,
+        default_buffer: BufferAttr,
+    ) -> Result<Self, PAErr> {
+        let spec = format.spec();
+        let s = open(
+            pulse,
+            Direction::Playback,
+            &pulse.playback,
+            "VBAN Output",
+            &spec,
+            default_buffer,
+        )?;
+        Ok(Self {
+            s,
+            playback: new_playback(format),
+            fade: StreamFade::new(
+                stream_fade.fade_ms,
+                format.rate,
+                usize::from(format.channels),
+            ),
+            silence: vec![0u8; spec.usec_to_bytes(MicroSeconds(SILENCE_CHUNK.as_micros() as u64))],
         })
-        .expect("task panicked")?;
     }
+}
 
-    Ok(())
+/// Opens a stream as configured, with `default_name` and `default_buffer` filling in what isn't.
+fn open(
+    pulse: &PulseConfig,
+    direction: Direction,
+    stream: &PulseStreamConfig,
+    default_name: &str,
+    spec: &Spec,
+    default_buffer: BufferAttr,
+) -> Result<Simple, PAErr> {
+    let buffer = &stream.buffer;
+    Simple::new(
+        pulse.server.as_deref(),
+        &pulse.application_name,
+        direction,
+        stream.device.as_deref(),
+        stream.stream_name.as_deref().unwrap_or(default_name),
+        spec,
+        None,
+        Some(&BufferAttr {
+            maxlength: buffer.maxlength.unwrap_or(default_buffer.maxlength),
+            tlength: buffer.tlength.unwrap_or(default_buffer.tlength),
+            prebuf: buffer.prebuf.unwrap_or(default_buffer.prebuf),
+            minreq: buffer.minreq.unwrap_or(default_buffer.minreq),
+            fragsize: buffer.fragsize.unwrap_or(default_buffer.fragsize),
+        }),
+    )
+}
+
+/// Writes audio to PulseAudio, telling the echo canceller when it'll be heard.
+fn play(
+    s: &Simple,
+    echo_reference: Option<&EchoReference>,
+    format: AudioFormat,
+    buffer: &[u8],
+) -> Result<(), PAErr> {
+    if buffer.is_empty() {
+        return Ok(());
+    }
+    // The canceller only follows audio at the rate it captures at.
+    if let Some(reference) = echo_reference.filter(|r| r.sample_rate() == format.rate) {
+        let heard_at = Instant::now() + Duration::from(s.get_latency()?);
+        reference.played(buffer, usize::from(format.channels), heard_at);
+    }
+    tokio::task::block_in_place(|| s.write(buffer))
 }
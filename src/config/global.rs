@@ -1,7 +1,11 @@
 use crate::asciistackstr::AsciiStackString;
+use crate::config::source_policy::{SourceNet, SourcePolicy};
+use crate::control::GAIN_DB_RANGE;
+use crate::vban::packet::DataType;
 use directories::ProjectDirs;
 use serde::Deserialize;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -12,16 +16,27 @@ pub enum ConfigError {
     Read(std::path::PathBuf, #[source] std::io::Error),
     #[error("Failed to parse {0}: {1}")]
     Parse(std::path::PathBuf, #[source] toml::de::Error),
+    #[error("Invalid config in {0}: {1}")]
+    Invalid(std::path::PathBuf, String),
+}
+
+fn project_dirs() -> Result<ProjectDirs, ConfigError> {
+    ProjectDirs::from("net.octyl", "Octavia Togami", "audio-bicycle")
+        .ok_or(ConfigError::UnknownHomeDirectory)
 }
 
 pub fn load_config() -> Result<GlobalConfig, ConfigError> {
-    let dirs = ProjectDirs::from("net.octyl", "Octavia Togami", "audio-bicycle")
-        .ok_or(ConfigError::UnknownHomeDirectory)?;
+    let dirs = project_dirs()?;
 
     let config_file = dirs.config_dir().join("config.toml");
     let config_text = std::fs::read_to_string(&config_file)
         .map_err(|e| ConfigError::Read(config_file.clone(), e))?;
-    toml::from_str(&config_text).map_err(|e| ConfigError::Parse(config_file, e))
+    let config: GlobalConfig =
+        toml::from_str(&config_text).map_err(|e| ConfigError::Parse(config_file.clone(), e))?;
+    config
+        .validate()
+        .map_err(|e| ConfigError::Invalid(config_file, e))?;
+    Ok(config)
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -29,4 +44,579 @@ pub struct GlobalConfig {
     pub local_address: SocketAddr,
     pub dest_address: SocketAddr,
     pub stream_name: AsciiStackString<16>,
+    /// The format of audio we capture and send.
+    #[serde(default)]
+    pub format: FormatConfig,
+    /// Which PulseAudio server and devices to use, and how.
+    #[serde(default)]
+    pub pulse: PulseConfig,
+    /// Streams to receive at the same time, mixed together or played on separate sinks. Defaults to just
+    /// `stream_name`, played on its own.
+    #[serde(default)]
+    pub receive: Vec<ReceiveStreamConfig>,
+    /// Which peers to accept packets from. Defaults to only `dest_address`, and the `from` addresses in `receive`.
+    pub source_policy: Option<SourcePolicy>,
+    /// Encrypts and authenticates packets, if set.
+    pub encryption: Option<EncryptionConfig>,
+    /// Compresses outgoing audio with Opus, if set.
+    pub opus: Option<OpusConfig>,
+    /// Sends parity packets for forward error correction, if set.
+    pub fec: Option<FecConfig>,
+    /// Serves Prometheus metrics, if set.
+    pub metrics: Option<MetricsConfig>,
+    /// Where to listen for control commands. Defaults to `control.sock` in the runtime directory.
+    pub control_socket: Option<PathBuf>,
+    /// Accepts OSC control messages, if set.
+    pub osc: Option<OscConfig>,
+    /// Stops sending audio while the room is quiet, if set.
+    pub gate: Option<GateConfig>,
+    /// Removes the echo of received audio from the captured audio, if set. Only needed with speakers.
+    pub aec: Option<AecConfig>,
+    /// Suppresses steady background noise in the captured audio, if set.
+    pub denoise: Option<DenoiseConfig>,
+    /// Steers the level of the captured audio towards a target, if set.
+    pub agc: Option<AgcConfig>,
+    /// Turns playback down while we're talking, if set.
+    pub duck: Option<DuckConfig>,
+    /// Captures from several sources at once and sends them together, if set.
+    pub capture_mix: Option<CaptureMixConfig>,
+    /// Plays a quiet copy of the captured audio locally, if set.
+    pub sidetone: Option<SidetoneConfig>,
+    /// How received audio starts and stops.
+    #[serde(default)]
+    pub stream_fade: StreamFadeConfig,
+    /// How to notice things happening to the stream, and what to run when they do.
+    #[serde(default)]
+    pub events: EventsConfig,
+    /// Processing for audio we capture and send.
+    #[serde(default)]
+    pub capture: DirectionConfig,
+    /// Processing for audio we receive and play.
+    #[serde(default)]
+    pub playback: DirectionConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EncryptionConfig {
+    /// A file containing the pre-shared key, as 64 hex digits.
+    pub key_file: PathBuf,
+    /// Where to remember the newest sessions sent and received, so old ones can't be replayed after a restart.
+    /// Defaults to `sessions.json` in the data directory.
+    pub session_file: Option<PathBuf>,
+}
+
+impl EncryptionConfig {
+    pub fn session_file(&self) -> Result<PathBuf, ConfigError> {
+        match &self.session_file {
+            Some(path) => Ok(path.clone()),
+            None => Ok(project_dirs()?.data_local_dir().join("sessions.json")),
+        }
+    }
+}
+
+impl GlobalConfig {
+    pub fn source_policy(&self) -> SourcePolicy {
+        self.source_policy.clone().unwrap_or_else(|| {
+            let peers = self.receive.iter().filter_map(|stream| stream.from);
+            SourcePolicy::only(std::iter::once(self.dest_address.ip()).chain(peers))
+        })
+    }
+
+    pub fn control_socket(&self) -> Result<PathBuf, ConfigError> {
+        if let Some(path) = &self.control_socket {
+            return Ok(path.clone());
+        }
+        let dirs = project_dirs()?;
+        // There's no runtime directory outside of XDG, so fall back to the cache.
+        let dir = dirs.runtime_dir().unwrap_or_else(|| dirs.cache_dir());
+        Ok(dir.join("control.sock"))
+    }
+
+    /// Catches settings that parse, but can't be what was meant.
+    fn validate(&self) -> Result<(), String> {
+        for (name, direction) in [("capture", &self.capture), ("playback", &self.playback)] {
+            if !GAIN_DB_RANGE.contains(&direction.gain_db) {
+                return Err(format!(
+                    "{}.gain_db must be between {} and {} dB",
+                    name,
+                    GAIN_DB_RANGE.start(),
+                    GAIN_DB_RANGE.end()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FormatConfig {
+    /// Samples per second, per channel. This has to be a rate VBAN knows.
+    pub sample_rate: u32,
+    pub channels: u8,
+    /// How each sample is sent. This doesn't affect Opus.
+    pub sample_type: SampleType,
+    /// How much audio to send in each packet, in milliseconds. Defaults to as much as fits.
+    pub packet_ms: Option<f32>,
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 48000,
+            channels: 2,
+            sample_type: SampleType::I24,
+            packet_ms: None,
+        }
+    }
+}
+
+/// How samples are sent, as little-endian integers or floats.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleType {
+    U8,
+    I16,
+    I24,
+    I32,
+    F32,
+    F64,
+}
+
+impl From<SampleType> for DataType {
+    fn from(sample_type: SampleType) -> Self {
+        match sample_type {
+            SampleType::U8 => DataType::U8,
+            SampleType::I16 => DataType::I16,
+            SampleType::I24 => DataType::I24,
+            SampleType::I32 => DataType::I32,
+            SampleType::F32 => DataType::F32,
+            SampleType::F64 => DataType::F64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PulseConfig {
+    /// The server to connect to, e.g. `tcp:studio.local`. Defaults to the usual one.
+    pub server: Option<String>,
+    /// What we're called in PulseAudio.
+    pub application_name: String,
+    /// What our streams are for, e.g. `phone`, so PulseAudio can route and duck them.
+    pub media_role: Option<String>,
+    /// Creates a sink and source named after the stream, and uses those instead of the configured devices.
+    pub virtual_devices: bool,
+    pub capture: PulseStreamConfig,
+    pub playback: PulseStreamConfig,
+}
+
+impl Default for PulseConfig {
+    fn default() -> Self {
+        Self {
+            server: None,
+            application_name: "Audio Bicycle".to_string(),
+            media_role: None,
+            virtual_devices: false,
+            capture: PulseStreamConfig::default(),
+            playback: PulseStreamConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PulseStreamConfig {
+    /// The source or sink to use. Defaults to the default one.
+    pub device: Option<String>,
+    /// What the stream is called. Defaults to `VBAN Input` or `VBAN Output`.
+    pub stream_name: Option<String>,
+    pub buffer: BufferConfig,
+}
+
+/// PulseAudio's buffering attributes, in bytes. Any left out keep our default for them.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BufferConfig {
+    pub maxlength: Option<u32>,
+    pub tlength: Option<u32>,
+    pub prebuf: Option<u32>,
+    pub minreq: Option<u32>,
+    pub fragsize: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReceiveStreamConfig {
+    pub stream_name: AsciiStackString<16>,
+    /// Only accept this stream from this address, if set.
+    #[serde(default)]
+    pub from: Option<IpAddr>,
+    /// How much to turn this stream up or down, in dB.
+    #[serde(default)]
+    pub gain_db: f32,
+    /// Where to place this stream, from -1.0 for left to 1.0 for right.
+    #[serde(default)]
+    pub pan: f32,
+    /// How much audio to hold back to smooth out network jitter, in milliseconds.
+    #[serde(default = "default_receive_jitter_ms")]
+    pub jitter_ms: u32,
+    /// Plays this stream on its own on this sink, instead of in the mix.
+    #[serde(default)]
+    pub sink: Option<String>,
+}
+
+fn default_receive_jitter_ms() -> u32 {
+    40
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConfig {
+    /// Where to listen for HTTP requests for `/metrics`.
+    pub listen_address: SocketAddr,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DirectionConfig {
+    /// The gain to start with, in dB.
+    pub gain_db: f32,
+    /// Whether to start muted.
+    pub muted: bool,
+    /// Whether to start with the soft limiter on.
+    pub limiter: bool,
+    /// Where the soft limiter starts bending peaks down, in dBFS.
+    pub limiter_threshold_db: f32,
+    /// Filters to shape the audio with, applied in order.
+    pub eq: Vec<FilterConfig>,
+}
+
+impl Default for DirectionConfig {
+    fn default() -> Self {
+        Self {
+            gain_db: 0.0,
+            muted: false,
+            limiter: false,
+            limiter_threshold_db: -3.0,
+            eq: Vec::new(),
+        }
+    }
+}
+
+/// One filter in an EQ chain. Frequencies are in Hz, and `q` sets how wide the filter is, or how steep its slope.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FilterConfig {
+    /// Cuts everything below `frequency`.
+    HighPass {
+        frequency: f32,
+        #[serde(default = "default_slope_q")]
+        q: f32,
+    },
+    /// Cuts everything above `frequency`.
+    LowPass {
+        frequency: f32,
+        #[serde(default = "default_slope_q")]
+        q: f32,
+    },
+    /// Boosts or cuts around `frequency`.
+    Peaking {
+        frequency: f32,
+        gain_db: f32,
+        #[serde(default = "default_peaking_q")]
+        q: f32,
+    },
+    /// Boosts or cuts everything below `frequency`.
+    LowShelf {
+        frequency: f32,
+        gain_db: f32,
+        #[serde(default = "default_slope_q")]
+        q: f32,
+    },
+    /// Boosts or cuts everything above `frequency`.
+    HighShelf {
+        frequency: f32,
+        gain_db: f32,
+        #[serde(default = "default_slope_q")]
+        q: f32,
+    },
+}
+
+impl FilterConfig {
+    pub fn frequency(&self) -> f32 {
+        match *self {
+            Self::HighPass { frequency, .. }
+            | Self::LowPass { frequency, .. }
+            | Self::Peaking { frequency, .. }
+            | Self::LowShelf { frequency, .. }
+            | Self::HighShelf { frequency, .. } => frequency,
+        }
+    }
+
+    pub fn q(&self) -> f32 {
+        match *self {
+            Self::HighPass { q, .. }
+            | Self::LowPass { q, .. }
+            | Self::Peaking { q, .. }
+            | Self::LowShelf { q, .. }
+            | Self::HighShelf { q, .. } => q,
+        }
+    }
+
+    /// The boost or cut, in dB. Passes don't have one.
+    pub fn gain_db(&self) -> f32 {
+        match *self {
+            Self::HighPass { .. } | Self::LowPass { .. } => 0.0,
+            Self::Peaking { gain_db, .. }
+            | Self::LowShelf { gain_db, .. }
+            | Self::HighShelf { gain_db, .. } => gain_db,
+        }
+    }
+}
+
+/// A Butterworth response, with no bump at the corner.
+fn default_slope_q() -> f32 {
+    std::f32::consts::FRAC_1_SQRT_2
+}
+
+fn default_peaking_q() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GateConfig {
+    /// The level audio has to reach to open the gate, in dBFS.
+    pub threshold_db: f32,
+    /// How long the gate takes to open, in milliseconds.
+    pub attack_ms: f32,
+    /// How long the gate stays open after the audio drops under the threshold, in milliseconds.
+    pub hold_ms: f32,
+    /// How long the gate takes to close, in milliseconds.
+    pub release_ms: f32,
+    /// How often to tell the peer we're still here while the gate is shut, in milliseconds.
+    pub keepalive_ms: u64,
+}
+
+impl Default for GateConfig {
+    fn default() -> Self {
+        Self {
+            threshold_db: -50.0,
+            attack_ms: 5.0,
+            hold_ms: 200.0,
+            release_ms: 150.0,
+            keepalive_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DenoiseConfig {
+    /// How hard to suppress noise, from 0 to 1. Higher values remove more noise, but can make voices sound thinner.
+    pub strength: f32,
+    /// Whether to start with suppression on. It can be turned on and off while running without changing the latency.
+    pub enabled: bool,
+}
+
+impl Default for DenoiseConfig {
+    fn default() -> Self {
+        Self {
+            strength: 0.5,
+            enabled: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AecConfig {
+    /// The longest echo to cancel, in milliseconds. Longer tails handle bigger, more reverberant rooms, but take
+    /// longer to learn.
+    pub tail_ms: u32,
+}
+
+impl Default for AecConfig {
+    fn default() -> Self {
+        Self { tail_ms: 100 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AgcConfig {
+    /// The RMS level to aim for, in dBFS.
+    pub target_db: f32,
+    /// The most the AGC will boost or cut, in dB.
+    pub max_gain_db: f32,
+    /// How quickly the gain adapts, in dB per second.
+    pub speed_db_per_second: f32,
+}
+
+impl Default for AgcConfig {
+    fn default() -> Self {
+        Self {
+            target_db: -20.0,
+            max_gain_db: 20.0,
+            speed_db_per_second: 6.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DuckConfig {
+    /// The level captured audio has to reach to duck playback, in dBFS.
+    pub threshold_db: f32,
+    /// How far to turn playback down, in dB.
+    pub amount_db: f32,
+    /// How long playback takes to duck, in milliseconds.
+    pub attack_ms: f32,
+    /// How long playback takes to come back up once the captured audio drops under the threshold, in milliseconds.
+    pub release_ms: f32,
+}
+
+impl Default for DuckConfig {
+    fn default() -> Self {
+        Self {
+            threshold_db: -40.0,
+            amount_db: 12.0,
+            attack_ms: 20.0,
+            release_ms: 500.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CaptureMixConfig {
+    /// Whether the sources are mixed together, or each sent on channels of its own.
+    #[serde(default)]
+    pub layout: MixLayout,
+    pub sources: Vec<MixSourceConfig>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MixLayout {
+    #[default]
+    Mix,
+    Channels,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MixSourceConfig {
+    /// The source to capture, e.g. a sink's monitor. Defaults to the capture device.
+    pub device: Option<String>,
+    /// How much to turn this source up or down, in dB.
+    pub gain_db: f32,
+    /// How many channels this source takes up. Only used with the channels layout, where they have to add up to the
+    /// format's channels.
+    pub channels: Option<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SidetoneConfig {
+    /// How loud the captured audio is played back, in dB.
+    pub gain_db: f32,
+}
+
+impl Default for SidetoneConfig {
+    fn default() -> Self {
+        Self { gain_db: -20.0 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StreamFadeConfig {
+    /// How long received audio can stop for before it's treated as stopped, in milliseconds.
+    pub timeout_ms: u64,
+    /// How long to fade received audio in and out when it starts and stops, in milliseconds.
+    pub fade_ms: u32,
+}
+
+impl Default for StreamFadeConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 60,
+            fade_ms: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EventsConfig {
+    /// How long the peer can send nothing before it's considered gone, in milliseconds.
+    pub peer_timeout_ms: u64,
+    pub hooks: HooksConfig,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            peer_timeout_ms: 5000,
+            hooks: HooksConfig::default(),
+        }
+    }
+}
+
+/// Shell commands to run when things happen. Each is described to the command in `AUDIO_BICYCLE_*` environment
+/// variables.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    pub peer_appeared: Option<String>,
+    pub peer_silent: Option<String>,
+    pub format_changed: Option<String>,
+    pub auth_failure: Option<String>,
+    pub restart: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OscConfig {
+    /// Where to listen for OSC messages.
+    pub listen_address: SocketAddr,
+    /// How often to send level meters to subscribers, in milliseconds.
+    #[serde(default = "default_osc_meter_interval_ms")]
+    pub meter_interval_ms: u64,
+    /// Who else may subscribe, as addresses or CIDR ranges. This machine always may.
+    #[serde(default)]
+    pub subscribe_from: Vec<SourceNet>,
+}
+
+fn default_osc_meter_interval_ms() -> u64 {
+    100
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FecConfig {
+    /// How many audio packets each parity packet covers. Smaller groups recover more, but cost more bandwidth.
+    pub group_size: u8,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "opus"), allow(dead_code))]
+pub struct OpusConfig {
+    /// The target bitrate, in bits per second.
+    #[serde(default = "default_opus_bitrate")]
+    pub bitrate: u32,
+    /// How much audio goes in each packet, in milliseconds.
+    #[serde(default = "default_opus_frame_ms")]
+    pub frame_ms: f32,
+    /// Whether to add in-band forward error correction, so a lost packet can be partly recovered from the next one.
+    #[serde(default)]
+    pub fec: bool,
+    /// The packet loss to expect, as a percentage. Higher values make FEC more robust.
+    #[serde(default)]
+    pub expected_loss: u8,
+}
+
+fn default_opus_bitrate() -> u32 {
+    128_000
+}
+
+fn default_opus_frame_ms() -> f32 {
+    5.0
 }
@@ -1 +1,2 @@
 pub(crate) mod global;
+pub(crate) mod source_policy;
@@ -0,0 +1,114 @@
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::asciistackstr::AsciiStackString;
+
+/// Decides which peers the receiver accepts packets from.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SourcePolicy {
+    /// What to do with packets that match no rule.
+    #[serde(default)]
+    pub default: PolicyAction,
+    /// Rules to check, in order. The first rule that matches decides.
+    #[serde(default)]
+    pub rules: Vec<SourceRule>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    Allow,
+    #[default]
+    Deny,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SourceRule {
+    pub action: PolicyAction,
+    /// An IP address or CIDR range, e.g. `192.168.1.0/24`.
+    pub source: SourceNet,
+    /// The source ports this rule applies to. Empty means any port.
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    /// The stream names this rule applies to. Empty means any stream.
+    #[serde(default)]
+    pub streams: Vec<AsciiStackString<16>>,
+}
+
+/// An IP network, which can be written as either a CIDR range or a single address.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct SourceNet(IpNet);
+
+impl TryFrom<String> for SourceNet {
+    type Error = ipnet::AddrParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.parse::<IpAddr>() {
+            Ok(addr) => Ok(Self(IpNet::from(addr))),
+            Err(_) => value.parse().map(Self),
+        }
+    }
+}
+
+impl SourceNet {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // Dual-stack sockets report IPv4 peers as IPv4-mapped IPv6 addresses.
+        self.0.contains(&addr.to_canonical())
+    }
+}
+
+impl SourcePolicy {
+    /// The policy used when none is configured: only accept packets from the given addresses.
+    pub fn only(addrs: impl IntoIterator<Item = IpAddr>) -> Self {
+        Self {
+            default: PolicyAction::Deny,
+            rules: addrs
+                .into_iter()
+                .map(|addr| SourceRule {
+                    action: PolicyAction::Allow,
+                    source: SourceNet(IpNet::from(addr)),
+                    ports: Vec::new(),
+                    streams: Vec::new(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Could any stream from this address be allowed?
+    /// This lets us drop packets without decoding them first.
+    pub fn may_allow(&self, addr: SocketAddr) -> bool {
+        for rule in self.rules.iter().filter(|r| r.matches_addr(addr)) {
+            match rule.action {
+                PolicyAction::Allow => return true,
+                PolicyAction::Deny if rule.streams.is_empty() => return false,
+                // This only denies some streams, so keep looking.
+                PolicyAction::Deny => {}
+            }
+        }
+        self.default == PolicyAction::Allow
+    }
+
+    /// Is this address allowed to send this stream?
+    pub fn allows(&self, addr: SocketAddr, stream_name: &AsciiStackString<16>) -> bool {
+        let action = self
+            .rules
+            .iter()
+            .find(|r| r.matches_addr(addr) && r.matches_stream(stream_name))
+            .map_or(self.default, |r| r.action);
+        action == PolicyAction::Allow
+    }
+}
+
+impl SourceRule {
+    fn matches_addr(&self, addr: SocketAddr) -> bool {
+        self.source.contains(addr.ip())
+            && (self.ports.is_empty() || self.ports.contains(&addr.port()))
+    }
+
+    fn matches_stream(&self, stream_name: &AsciiStackString<16>) -> bool {
+        self.streams.is_empty() || self.streams.contains(stream_name)
+    }
+}
@@ -0,0 +1,108 @@
+//! Lets other processes inspect and adjust the running service, over a Unix socket.
+
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+
+use crate::config::global::{DirectionConfig, GlobalConfig};
+use crate::control::protocol::Direction;
+use crate::stats::Gauge;
+
+pub(crate) mod client;
+pub(crate) mod protocol;
+pub(crate) mod server;
+
+/// The gains that can be set, in dB. Anything outside this is more likely a typo than a wish.
+pub const GAIN_DB_RANGE: RangeInclusive<f32> = -100.0..=40.0;
+
+/// Settings that can be changed while running. This lives for the whole process, so they survive restarts.
+#[derive(Default)]
+pub struct Controls {
+    /// Applies to audio we capture and send.
+    pub capture: DirectionControls,
+    /// Applies to audio we receive and play.
+    pub playback: DirectionControls,
+    /// Whether noise suppression is on, if it's configured.
+    denoise: AtomicBool,
+    restart: Notify,
+    initialized: OnceLock<()>,
+}
+
+#[derive(Default)]
+pub struct DirectionControls {
+    muted: AtomicBool,
+    gain_db: Gauge,
+    limiter: AtomicBool,
+}
+
+impl Controls {
+    /// Takes the starting settings from the config. Only the first call does anything, so changes made while running
+    /// aren't undone by a restart.
+    pub fn initialize(&self, config: &GlobalConfig) {
+        self.initialized.get_or_init(|| {
+            self.capture.initialize(&config.capture);
+            self.playback.initialize(&config.playback);
+            self.set_denoise(config.denoise.as_ref().is_some_and(|d| d.enabled));
+        });
+    }
+
+    pub fn direction(&self, direction: Direction) -> &DirectionControls {
+        match direction {
+            Direction::Tx => &self.capture,
+            Direction::Rx => &self.playback,
+        }
+    }
+
+    pub fn denoise(&self) -> bool {
+        self.denoise.load(Ordering::Relaxed)
+    }
+
+    pub fn set_denoise(&self, denoise: bool) {
+        self.denoise.store(denoise, Ordering::Relaxed);
+    }
+
+    /// Restarts each run that's waiting, once. Nothing is kept for later, so a request can't also restart the next run.
+    pub fn request_restart(&self) {
+        self.restart.notify_waiters();
+    }
+
+    /// Completes when someone asks for a restart after this is called, even if it hasn't been polled yet.
+    pub fn restart_requested(&self) -> Notified<'_> {
+        self.restart.notified()
+    }
+}
+
+impl DirectionControls {
+    fn initialize(&self, config: &DirectionConfig) {
+        self.set_muted(config.muted);
+        self.set_gain_db(config.gain_db);
+        self.set_limiter(config.limiter);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    pub fn gain_db(&self) -> f32 {
+        self.gain_db.get() as f32
+    }
+
+    pub fn set_gain_db(&self, gain_db: f32) {
+        self.gain_db.set(f64::from(gain_db));
+    }
+
+    pub fn limiter(&self) -> bool {
+        self.limiter.load(Ordering::Relaxed)
+    }
+
+    pub fn set_limiter(&self, limiter: bool) {
+        self.limiter.store(limiter, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,46 @@
+use std::path::Path;
+
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+use crate::control::protocol::{Request, Response};
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Couldn't connect to {0}, is the service running? {1}")]
+    Connect(std::path::PathBuf, #[source] std::io::Error),
+    #[error("Couldn't talk to the service: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Service sent an invalid response: {0}")]
+    InvalidResponse(#[from] serde_json::Error),
+    #[error("Service closed the connection without responding")]
+    NoResponse,
+    #[error("Service refused the request: {0}")]
+    Refused(String),
+}
+
+/// Sends one request to the service at `socket_path`, and waits for its response.
+pub async fn send(socket_path: &Path, request: &Request) -> Result<Response, ClientError> {
+    let stream = UnixStream::connect(socket_path)
+        .await
+        .map_err(|e| ClientError::Connect(socket_path.to_path_buf(), e))?;
+    let (read, mut write) = stream.into_split();
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+    let line = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or(ClientError::NoResponse)?;
+    let response: Response = serde_json::from_str(&line)?;
+    if !response.ok {
+        return Err(ClientError::Refused(
+            response
+                .error
+                .unwrap_or_else(|| "no reason given".to_string()),
+        ));
+    }
+    Ok(response)
+}
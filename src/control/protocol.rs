@@ -0,0 +1,85 @@
+//! The control protocol: one JSON [Request] per line, each answered by one JSON [Response] line.
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::StatsSnapshot;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Audio we capture and send.
+    Tx,
+    /// Audio we receive and play.
+    Rx,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    Status,
+    Mute {
+        direction: Direction,
+        muted: bool,
+    },
+    Gain {
+        direction: Direction,
+        db: f32,
+    },
+    Limiter {
+        direction: Direction,
+        enabled: bool,
+    },
+    /// Turns noise suppression of captured audio on or off.
+    Denoise {
+        enabled: bool,
+    },
+    Restart,
+    /// The most recent log lines.
+    Log,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log: Option<Vec<String>>,
+}
+
+impl Response {
+    pub fn ok() -> Self {
+        Self {
+            ok: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn error(error: impl ToString) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    pub tx: DirectionStatus,
+    pub rx: DirectionStatus,
+    /// Whether noise suppression is on for audio we send.
+    pub denoise: bool,
+    /// Includes who we last accepted each received stream from.
+    pub stats: StatsSnapshot,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DirectionStatus {
+    pub muted: bool,
+    pub gain_db: f32,
+    pub limiter: bool,
+    /// The format of the audio on the wire, if known.
+    pub format: Option<String>,
+}
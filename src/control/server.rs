@@ -0,0 +1,140 @@
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::control::protocol::{DirectionStatus, Request, Response, Status};
+use crate::control::{Controls, GAIN_DB_RANGE};
+use crate::recent_log;
+use crate::stats::Stats;
+
+/// What the server needs to answer requests.
+pub struct ServerState {
+    pub controls: Arc<Controls>,
+    pub stats: Arc<Stats>,
+    /// The format we send in, e.g. `48000 Hz, 2 channels, I24, PCM`.
+    pub tx_format: String,
+}
+
+/// Answers control requests until the task is aborted.
+pub async fn serve(listener: UnixListener, state: ServerState) {
+    let state = Arc::new(state);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log::warn!("Failed to accept control connection: {}", e);
+                continue;
+            }
+        };
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &state).await {
+                log::debug!("Control connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle(stream: UnixStream, state: &ServerState) -> std::io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => execute(request, state),
+            Err(e) => Response::error(format_args!("invalid request: {}", e)),
+        };
+        let mut response = serde_json::to_string(&response).expect("responses always serialize");
+        response.push('\n');
+        write.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn execute(request: Request, state: &ServerState) -> Response {
+    match request {
+        Request::Status => Response {
+            status: Some(status(state)),
+            ..Response::ok()
+        },
+        Request::Mute { direction, muted } => {
+            log::info!(
+                "{} {:?} over the control socket",
+                if muted { "Muting" } else { "Unmuting" },
+                direction
+            );
+            state.controls.direction(direction).set_muted(muted);
+            Response::ok()
+        }
+        Request::Gain { direction, db } => {
+            if !GAIN_DB_RANGE.contains(&db) {
+                return Response::error(format!(
+                    "gain must be between {} and {} dB",
+                    GAIN_DB_RANGE.start(),
+                    GAIN_DB_RANGE.end()
+                ));
+            }
+            log::info!(
+                "Setting {:?} gain to {} dB over the control socket",
+                direction,
+                db
+            );
+            state.controls.direction(direction).set_gain_db(db);
+            Response::ok()
+        }
+        Request::Limiter { direction, enabled } => {
+            log::info!(
+                "Turning {:?} limiter {} over the control socket",
+                direction,
+                if enabled { "on" } else { "off" }
+            );
+            state.controls.direction(direction).set_limiter(enabled);
+            Response::ok()
+        }
+        Request::Denoise { enabled } => {
+            log::info!(
+                "Turning noise suppression {} over the control socket",
+                if enabled { "on" } else { "off" }
+            );
+            state.controls.set_denoise(enabled);
+            Response::ok()
+        }
+        Request::Restart => {
+            log::info!("Restart requested over the control socket");
+            state.controls.request_restart();
+            Response::ok()
+        }
+        Request::Log => Response {
+            log: Some(recent_log::recent()),
+            ..Response::ok()
+        },
+    }
+}
+
+fn status(state: &ServerState) -> Status {
+    let stats = state.stats.snapshot();
+    // With several streams, the first one being received stands for them all.
+    let rx_format = stats
+        .streams
+        .iter()
+        .find_map(|stream| Some(stream.peer.as_ref()?.format.clone()));
+    Status {
+        tx: DirectionStatus {
+            muted: state.controls.capture.muted(),
+            gain_db: state.controls.capture.gain_db(),
+            limiter: state.controls.capture.limiter(),
+            format: Some(state.tx_format.clone()),
+        },
+        rx: DirectionStatus {
+            muted: state.controls.playback.muted(),
+            gain_db: state.controls.playback.gain_db(),
+            limiter: state.controls.playback.limiter(),
+            format: rx_format,
+        },
+        denoise: state.controls.denoise(),
+        stats,
+    }
+}
@@ -0,0 +1,212 @@
+//! Processing applied to audio on its way in or out.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::config::global::{
+    AecConfig, AgcConfig, DenoiseConfig, DirectionConfig, DuckConfig, GateConfig,
+};
+use crate::control::protocol::Direction;
+use crate::control::{Controls, DirectionControls};
+use crate::dsp::agc::Agc;
+use crate::dsp::denoise::Denoiser;
+use crate::dsp::duck::Ducker;
+use crate::dsp::echo::{EchoCanceller, EchoReference};
+use crate::dsp::eq::Equalizer;
+use crate::dsp::gain::Gain;
+use crate::dsp::gate::NoiseGate;
+use crate::dsp::limiter::SoftLimiter;
+use crate::pcm::{f32_to_s24le, s24le_to_f32};
+use crate::stats::Gauge;
+
+pub(crate) mod agc;
+pub(crate) mod denoise;
+pub(crate) mod duck;
+pub(crate) mod echo;
+pub(crate) mod eq;
+pub(crate) mod fade;
+pub(crate) mod gain;
+pub(crate) mod gate;
+pub(crate) mod limiter;
+pub(crate) mod mix;
+pub(crate) mod sidetone;
+pub(crate) mod stream_mix;
+
+/// The processing for one direction, applied to each buffer of 24-bit samples as it passes through.
+pub struct Pipeline {
+    controls: Arc<Controls>,
+    direction: Direction,
+    sample_rate: u32,
+    channels: usize,
+    echo: Option<EchoCanceller>,
+    eq: Equalizer,
+    gate: Option<NoiseGate>,
+    denoiser: Option<Denoiser>,
+    agc: Option<Agc>,
+    gain: Gain,
+    ducker: Option<Ducker>,
+    /// Where to publish the peak level of each processed buffer, for ducking the other direction.
+    duck_key: Option<Arc<Gauge>>,
+    limiter: SoftLimiter,
+    samples: Vec<f32>,
+    bytes: Vec<u8>,
+}
+
+impl Pipeline {
+    pub fn new(
+        controls: Arc<Controls>,
+        direction: Direction,
+        config: &DirectionConfig,
+        sample_rate: u32,
+        channels: usize,
+    ) -> Self {
+        let gain = Gain::new(sample_rate, target_gain(controls.direction(direction)));
+        Self {
+            controls,
+            direction,
+            sample_rate,
+            channels,
+            echo: None,
+            eq: Equalizer::new(&config.eq, sample_rate, channels),
+            gate: None,
+            denoiser: None,
+            agc: None,
+            gain,
+            ducker: None,
+            duck_key: None,
+            limiter: SoftLimiter::new(config.limiter_threshold_db),
+            samples: Vec::new(),
+            bytes: Vec::new(),
+        }
+    }
+
+    /// Adds echo cancellation against what `reference` says was played, in front of everything else. This delays the
+    /// audio by a fixed amount.
+    pub fn with_echo_canceller(
+        mut self,
+        config: &AecConfig,
+        reference: Arc<EchoReference>,
+    ) -> Self {
+        let echo = EchoCanceller::new(config, reference, self.sample_rate, self.channels);
+        log::info!(
+            "Echo cancellation adds {:.1} ms of latency",
+            echo.latency_frames() as f32 * 1000.0 / self.sample_rate as f32
+        );
+        self.echo = Some(echo);
+        self
+    }
+
+    /// Adds a noise gate, after noise suppression.
+    pub fn with_gate(mut self, config: &GateConfig) -> Self {
+        self.gate = Some(NoiseGate::new(config, self.sample_rate));
+        self
+    }
+
+    /// Adds noise suppression, after echo cancellation and EQ. This delays the audio by a fixed amount.
+    pub fn with_denoiser(mut self, config: &DenoiseConfig) -> Self {
+        let denoiser = Denoiser::new(config, self.sample_rate, self.channels);
+        log::info!(
+            "Noise suppression adds {:.1} ms of latency",
+            denoiser.latency_frames() as f32 * 1000.0 / self.sample_rate as f32
+        );
+        self.denoiser = Some(denoiser);
+        self
+    }
+
+    /// Adds automatic gain control, after the noise gate.
+    pub fn with_agc(mut self, config: &AgcConfig) -> Self {
+        self.agc = Some(Agc::new(config, self.sample_rate));
+        self
+    }
+
+    /// Adds ducking, after the gain, driven by the level another pipeline publishes to `key`.
+    pub fn with_ducker(mut self, config: &DuckConfig, key: Arc<Gauge>) -> Self {
+        self.ducker = Some(Ducker::new(config, key, self.sample_rate));
+        self
+    }
+
+    /// Publishes the peak level of each processed buffer to `key`, to drive another pipeline's ducker.
+    pub fn with_duck_key(mut self, key: Arc<Gauge>) -> Self {
+        self.duck_key = Some(key);
+        self
+    }
+
+    /// The gain the AGC applied to the last buffer, in dB, if there's an AGC.
+    pub fn agc_gain_db(&self) -> Option<f32> {
+        self.agc.as_ref().map(Agc::gain_db)
+    }
+
+    /// How much echo cancellation is taking out, in dB, if there's an echo canceller.
+    pub fn echo_reduction_db(&self) -> Option<f32> {
+        self.echo.as_ref().map(EchoCanceller::reduction_db)
+    }
+
+    /// Processes a buffer whose first sample was captured, or will be heard, at `at`.
+    ///
+    /// Returns false if the noise gate is shut, so the buffer is silence that needn't be sent.
+    pub fn process(&mut self, buffer: &mut [u8], at: Instant) -> bool {
+        let controls = self.controls.direction(self.direction);
+        self.gain.set_target(target_gain(controls));
+        let limit = controls.limiter();
+        if self.gain.is_unity()
+            && !limit
+            && self.echo.is_none()
+            && self.eq.is_empty()
+            && self.gate.is_none()
+            && self.denoiser.is_none()
+            && self.agc.is_none()
+            && self.ducker.is_none()
+            && self.duck_key.is_none()
+        {
+            return true;
+        }
+
+        self.samples.clear();
+        s24le_to_f32(buffer, &mut self.samples);
+        if let Some(echo) = &mut self.echo {
+            echo.process(&mut self.samples, at);
+        }
+        self.eq.process(&mut self.samples, self.channels);
+        if let Some(denoiser) = &mut self.denoiser {
+            // This has to run even when bypassed, so the delay stays the same.
+            denoiser.process(&mut self.samples, !self.controls.denoise());
+        }
+        let open = match &mut self.gate {
+            Some(gate) => gate.process(&mut self.samples, self.channels),
+            None => true,
+        };
+        if let Some(agc) = &mut self.agc {
+            // Hold the gain while the gate is shut, there's nothing to measure.
+            if open {
+                agc.process(&mut self.samples, self.channels);
+            }
+        }
+        self.gain.process(&mut self.samples, self.channels);
+        if let Some(ducker) = &mut self.ducker {
+            ducker.process(&mut self.samples, self.channels);
+        }
+        if limit {
+            self.limiter.process(&mut self.samples);
+        }
+        if let Some(key) = &self.duck_key {
+            let peak = self
+                .samples
+                .iter()
+                .fold(0.0f32, |peak, s| peak.max(s.abs()));
+            key.set(f64::from(peak));
+        }
+        self.bytes.clear();
+        f32_to_s24le(&self.samples, &mut self.bytes);
+        buffer[..self.bytes.len()].copy_from_slice(&self.bytes);
+        open
+    }
+}
+
+/// The linear gain `controls` ask for.
+fn target_gain(controls: &DirectionControls) -> f32 {
+    if controls.muted() {
+        0.0
+    } else {
+        10f32.powf(controls.gain_db() / 20.0)
+    }
+}
@@ -0,0 +1,62 @@
+use crate::config::global::AgcConfig;
+
+/// Audio quieter than this is left alone, so the AGC doesn't turn up the room noise between words.
+const SILENCE_DB: f32 = -60.0;
+
+/// Slowly steers the level of audio towards a target.
+pub struct Agc {
+    target_db: f32,
+    max_gain_db: f32,
+    /// How far the gain may move each second, in dB.
+    speed_db: f32,
+    sample_rate: f32,
+    gain_db: f32,
+}
+
+impl Agc {
+    pub fn new(config: &AgcConfig, sample_rate: u32) -> Self {
+        Self {
+            target_db: config.target_db,
+            max_gain_db: config.max_gain_db.max(0.0),
+            speed_db: config.speed_db_per_second.max(0.0),
+            sample_rate: sample_rate as f32,
+            gain_db: 0.0,
+        }
+    }
+
+    /// The gain applied to the last buffer, in dB.
+    pub fn gain_db(&self) -> f32 {
+        self.gain_db
+    }
+
+    /// Adjusts interleaved samples, ramping across them from the last gain to the new one.
+    pub fn process(&mut self, samples: &mut [f32], channels: usize) {
+        if samples.is_empty() {
+            return;
+        }
+        let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+        let rms_db = 20.0 * rms.max(1e-9).log10();
+
+        let previous = self.gain_db;
+        if rms_db > SILENCE_DB {
+            let wanted = (self.target_db - rms_db).clamp(-self.max_gain_db, self.max_gain_db);
+            let frames = (samples.len() / channels) as f32;
+            let max_step = self.speed_db * frames / self.sample_rate;
+            self.gain_db += (wanted - self.gain_db).clamp(-max_step, max_step);
+        }
+        // Don't push a sudden loud sound into clipping while waiting to adapt.
+        let headroom_db = -20.0 * peak.max(1e-9).log10();
+        self.gain_db = self.gain_db.min(headroom_db);
+
+        let from = 10f32.powf(previous / 20.0);
+        let to = 10f32.powf(self.gain_db / 20.0);
+        let frames = samples.len() / channels;
+        for (i, frame) in samples.chunks_mut(channels).enumerate() {
+            let gain = from + (to - from) * (i + 1) as f32 / frames as f32;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+}
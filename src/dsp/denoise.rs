@@ -0,0 +1,173 @@
+use std::sync::Arc;
+
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+
+use crate::config::global::DenoiseConfig;
+
+/// How much audio each spectrum covers, in milliseconds. This is also the latency we add.
+const FRAME_MS: u32 = 10;
+/// How much the noise estimate may rise each second, in dB. Falling is instant.
+const NOISE_RISE_DB_PER_SECOND: f32 = 3.0;
+/// The quietest a frequency gets is well under its average noise level, so the estimate is scaled up by this much.
+const NOISE_BIAS: f32 = 2.0;
+
+/// Suppresses steady background noise, like fans and air conditioning, by spectral subtraction.
+///
+/// Each channel is cut into overlapping frames, and each frequency is turned down by how much of it looks like the
+/// noise floor. That floor tracks the quietest each frequency has been recently, so speech doesn't count towards it.
+pub struct Denoiser {
+    frame_len: usize,
+    hop: usize,
+    window: Vec<f32>,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    /// How far over the noise floor to subtract.
+    over_subtraction: f32,
+    /// The least any frequency is turned down to, as a gain.
+    floor: f32,
+    /// How much the noise estimate may rise each frame, as a power ratio.
+    noise_rise: f32,
+    channels: Vec<ChannelState>,
+    frame: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+struct ChannelState {
+    /// The most recent `frame_len` samples.
+    input: Vec<f32>,
+    /// Processed frames being overlapped and added together.
+    overlap: Vec<f32>,
+    /// Finished output, waiting to be handed back.
+    pending: Vec<f32>,
+    /// How many samples of this hop have come in.
+    position: usize,
+    /// The power of each frequency, averaged over a few frames. Empty until the first frame.
+    power: Vec<f32>,
+    noise: Vec<f32>,
+    gains: Vec<f32>,
+}
+
+impl Denoiser {
+    pub fn new(config: &DenoiseConfig, sample_rate: u32, channels: usize) -> Self {
+        let frame_len = (sample_rate * FRAME_MS / 1000) as usize & !1;
+        let hop = frame_len / 2;
+        let mut planner = RealFftPlanner::new();
+        let forward = planner.plan_fft_forward(frame_len);
+        let inverse = planner.plan_fft_inverse(frame_len);
+        // The square root of a Hann window, used on the way in and out, so overlapping frames add up to the input.
+        let window = (0..frame_len)
+            .map(|i| {
+                let phase = std::f32::consts::PI * i as f32 / frame_len as f32;
+                phase.sin()
+            })
+            .collect();
+        let bins = frame_len / 2 + 1;
+        let strength = config.strength.clamp(0.0, 1.0);
+        let hops_per_second = sample_rate as f32 / hop as f32;
+        let scratch_len = forward.get_scratch_len().max(inverse.get_scratch_len());
+        Self {
+            frame_len,
+            hop,
+            window,
+            over_subtraction: 1.0 + 2.0 * strength,
+            floor: 10f32.powf(-30.0 * strength / 20.0),
+            noise_rise: 10f32.powf(NOISE_RISE_DB_PER_SECOND / 10.0 / hops_per_second),
+            channels: (0..channels)
+                .map(|_| ChannelState {
+                    input: vec![0.0; frame_len],
+                    overlap: vec![0.0; frame_len],
+                    pending: vec![0.0; hop],
+                    position: 0,
+                    power: Vec::new(),
+                    noise: vec![f32::MAX; bins],
+                    gains: vec![1.0; bins],
+                })
+                .collect(),
+            forward,
+            inverse,
+            frame: vec![0.0; frame_len],
+            spectrum: vec![Complex::default(); bins],
+            scratch: vec![Complex::default(); scratch_len],
+        }
+    }
+
+    /// The delay this adds, in frames.
+    pub fn latency_frames(&self) -> usize {
+        self.frame_len
+    }
+
+    /// Processes interleaved samples. With `bypass` set, noise is still tracked, but nothing is suppressed, so turning
+    /// this on and off doesn't change the latency.
+    pub fn process(&mut self, samples: &mut [f32], bypass: bool) {
+        let channels = self.channels.len();
+        for frame in samples.chunks_mut(channels) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let state = &mut self.channels[channel];
+                let hop_start = self.frame_len - self.hop;
+                state.input[hop_start + state.position] = *sample;
+                *sample = state.pending[state.position];
+                state.position += 1;
+                if state.position == self.hop {
+                    state.position = 0;
+                    self.process_frame(channel, bypass);
+                }
+            }
+        }
+    }
+
+    fn process_frame(&mut self, channel: usize, bypass: bool) {
+        let state = &mut self.channels[channel];
+        for ((out, input), window) in self.frame.iter_mut().zip(&state.input).zip(&self.window) {
+            *out = input * window;
+        }
+        self.forward
+            .process_with_scratch(&mut self.frame, &mut self.spectrum, &mut self.scratch)
+            .expect("buffers are the planned size");
+        if state.power.is_empty() {
+            state.power = self.spectrum.iter().map(|bin| bin.norm_sqr()).collect();
+        }
+
+        for (((bin, smoothed), noise), gain) in self
+            .spectrum
+            .iter_mut()
+            .zip(&mut state.power)
+            .zip(&mut state.noise)
+            .zip(&mut state.gains)
+        {
+            let power = bin.norm_sqr();
+            *smoothed = 0.9 * *smoothed + 0.1 * power;
+            // The floor lets the estimate climb back up after digital silence.
+            *noise = (*noise * self.noise_rise).max(1e-10).min(*smoothed);
+            let wanted = if bypass || power <= 0.0 {
+                1.0
+            } else {
+                (1.0 - self.over_subtraction * NOISE_BIAS * *noise / power)
+                    .max(0.0)
+                    .sqrt()
+                    .max(self.floor)
+            };
+            // Smoothing the gains over time avoids the warbling "musical noise" of plain subtraction.
+            *gain = 0.5 * *gain + 0.5 * wanted;
+            *bin *= *gain;
+        }
+        // These have to be real for the inverse transform.
+        self.spectrum[0].im = 0.0;
+        self.spectrum.last_mut().unwrap().im = 0.0;
+        self.inverse
+            .process_with_scratch(&mut self.spectrum, &mut self.frame, &mut self.scratch)
+            .expect("buffers are the planned size");
+
+        let scale = 1.0 / self.frame_len as f32;
+        for ((overlap, out), window) in state.overlap.iter_mut().zip(&self.frame).zip(&self.window)
+        {
+            *overlap += out * window * scale;
+        }
+        state.pending.copy_from_slice(&state.overlap[..self.hop]);
+        state.overlap.copy_within(self.hop.., 0);
+        let overlap_len = state.overlap.len();
+        state.overlap[overlap_len - self.hop..].fill(0.0);
+        state.input.copy_within(self.hop.., 0);
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use crate::config::global::DuckConfig;
+use crate::stats::Gauge;
+
+/// Turns playback down while our own captured audio is over a threshold, so we don't talk over the far end.
+pub struct Ducker {
+    /// The peak level of the most recent captured buffer, as a fraction of full scale.
+    key: Arc<Gauge>,
+    threshold: f32,
+    /// The gain while fully ducked.
+    ducked: f32,
+    /// How much the gain moves each frame while ducking and recovering.
+    attack_step: f32,
+    release_step: f32,
+    gain: f32,
+}
+
+impl Ducker {
+    pub fn new(config: &DuckConfig, key: Arc<Gauge>, sample_rate: u32) -> Self {
+        let ducked = 10f32.powf(-config.amount_db.abs() / 20.0);
+        let frames = |ms: f32| (ms * sample_rate as f32 / 1000.0).max(1.0);
+        Self {
+            key,
+            threshold: 10f32.powf(config.threshold_db / 20.0),
+            ducked,
+            attack_step: (1.0 - ducked) / frames(config.attack_ms),
+            release_step: (1.0 - ducked) / frames(config.release_ms),
+            gain: 1.0,
+        }
+    }
+
+    /// Ducks interleaved samples, depending on how loud the captured audio is right now.
+    pub fn process(&mut self, samples: &mut [f32], channels: usize) {
+        let active = self.key.get() as f32 >= self.threshold;
+        if !active && self.gain == 1.0 {
+            return;
+        }
+        for frame in samples.chunks_mut(channels) {
+            self.gain = if active {
+                (self.gain - self.attack_step).max(self.ducked)
+            } else {
+                (self.gain + self.release_step).min(1.0)
+            };
+            for sample in frame {
+                *sample *= self.gain;
+            }
+        }
+    }
+}
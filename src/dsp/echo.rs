@@ -0,0 +1,374 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+
+use crate::config::global::AecConfig;
+use crate::pcm::s24le_to_f32;
+
+/// How many frames the filter works on at a time. This is also the latency the canceller adds.
+const BLOCK: usize = 256;
+/// How far ahead of the mic the reference is taken, so an echo that seems to arrive a little early is still caught.
+const LEAD: Duration = Duration::from_millis(10);
+/// How much reference audio to keep, waiting for the mic to catch up.
+const MAX_REFERENCE: Duration = Duration::from_secs(2);
+/// How far a stream can drift from its latency estimates before its clock is started over.
+const MAX_DRIFT: Duration = Duration::from_millis(20);
+/// How much of the difference from each latency estimate to take on. They jitter, so trust them only on average.
+const DRIFT_CORRECTION: f64 = 0.002;
+/// How many frames the two streams can drift apart before they're lined up again. Every shift makes the filter
+/// relearn, so small ones are left alone.
+const MAX_SLIP: i64 = 4;
+/// How much of the filter's estimate to correct each block.
+const STEP_SIZE: f32 = 0.5;
+
+/// What was played, and when it was heard, so it can be lined up with what the mic picked up.
+pub struct EchoReference {
+    sample_rate: u32,
+    timeline: Mutex<Timeline>,
+}
+
+#[derive(Default)]
+struct Timeline {
+    /// Mono samples, in the order they're played.
+    samples: VecDeque<f32>,
+    /// The index of the first sample.
+    first: i64,
+    /// When the sample at index 0 was heard.
+    epoch: Option<Instant>,
+}
+
+impl EchoReference {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            timeline: Mutex::default(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn duration(&self, frames: i64) -> Duration {
+        Duration::from_secs_f64(frames as f64 / f64::from(self.sample_rate))
+    }
+
+    fn frames(&self, duration: Duration) -> i64 {
+        (duration.as_secs_f64() * f64::from(self.sample_rate)).round() as i64
+    }
+
+    /// Records interleaved 24-bit audio that will be heard starting at `at`.
+    pub fn played(&self, bytes: &[u8], channels: usize, at: Instant) {
+        let mut samples = Vec::with_capacity(bytes.len() / 3);
+        s24le_to_f32(bytes, &mut samples);
+        let mut timeline = self.timeline.lock().unwrap();
+        let next = timeline.first + timeline.samples.len() as i64;
+        match timeline.epoch.map(|epoch| epoch + self.duration(next)) {
+            Some(expected) => match follow(expected, at) {
+                Some(expected) => timeline.epoch = Some(expected - self.duration(next)),
+                // Nothing was played in between, so that was silence.
+                None if at > expected && at - expected < MAX_REFERENCE => {
+                    let gap = self.frames(at - expected) as usize;
+                    timeline.samples.extend(std::iter::repeat_n(0.0, gap));
+                }
+                None => *timeline = Timeline::starting_at(at),
+            },
+            None => *timeline = Timeline::starting_at(at),
+        }
+        timeline.samples.extend(
+            samples
+                .chunks_exact(channels)
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+        );
+        let max = self.frames(MAX_REFERENCE) as usize;
+        if timeline.samples.len() > max {
+            let excess = timeline.samples.len() - max;
+            timeline.samples.drain(..excess);
+            timeline.first += excess as i64;
+        }
+    }
+
+    /// The index of the sample heard at `at`, if anything's been played.
+    fn index_at(&self, at: Instant) -> Option<i64> {
+        let epoch = self.timeline.lock().unwrap().epoch?;
+        Some(if at >= epoch {
+            self.frames(at - epoch)
+        } else {
+            -self.frames(epoch - at)
+        })
+    }
+
+    /// Fills `out` with the samples starting at `index`, with silence for anything unknown.
+    fn read(&self, index: i64, out: &mut [f32]) {
+        out.fill(0.0);
+        let mut timeline = self.timeline.lock().unwrap();
+        // The mic won't need anything older again.
+        let stale = (index - timeline.first).clamp(0, timeline.samples.len() as i64);
+        timeline.samples.drain(..stale as usize);
+        timeline.first += stale;
+        // Anything left starts at `index` or later.
+        let offset = timeline.first - index;
+        if (0..out.len() as i64).contains(&offset) {
+            for (out, sample) in out[offset as usize..].iter_mut().zip(&timeline.samples) {
+                *out = *sample;
+            }
+        }
+    }
+}
+
+impl Timeline {
+    fn starting_at(at: Instant) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            first: 0,
+            epoch: Some(at),
+        }
+    }
+}
+
+/// Moves `expected` a little towards `measured`, or returns `None` if they're too far apart to be the same stream.
+fn follow(expected: Instant, measured: Instant) -> Option<Instant> {
+    if measured >= expected {
+        let error = measured - expected;
+        (error < MAX_DRIFT).then(|| expected + error.mul_f64(DRIFT_CORRECTION))
+    } else {
+        let error = expected - measured;
+        (error < MAX_DRIFT).then(|| expected - error.mul_f64(DRIFT_CORRECTION))
+    }
+}
+
+/// Removes the echo of what we played from what the mic picked up, with an adaptive filter.
+///
+/// This is a partitioned-block frequency-domain NLMS filter. It stops adapting while both ends talk at once, so the
+/// near end's voice doesn't get learned as echo.
+pub struct EchoCanceller {
+    reference: Arc<EchoReference>,
+    channels: usize,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    /// The spectra of the most recent reference blocks, newest first, each with the block before it.
+    history: VecDeque<Vec<Complex<f32>>>,
+    /// The loudest reference sample of each block in `history`.
+    history_peaks: VecDeque<f32>,
+    /// The previous reference block.
+    last_reference: Vec<f32>,
+    /// For each mic channel, the filter, as the spectrum of each partition.
+    filters: Vec<Vec<Vec<Complex<f32>>>>,
+    /// Mic and reference samples waiting for a full block, and output waiting to be handed back.
+    mic_in: Vec<f32>,
+    reference_in: Vec<f32>,
+    out: VecDeque<f32>,
+    /// How many frames have been captured, and when the first of them was.
+    captured: i64,
+    capture_epoch: Option<Instant>,
+    /// The reference index that lines up with each captured frame, less that frame's index.
+    reference_offset: Option<i64>,
+    /// Smoothed mic and output power, to report how much echo is removed.
+    mic_power: f32,
+    out_power: f32,
+    frame: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl EchoCanceller {
+    pub fn new(
+        config: &AecConfig,
+        reference: Arc<EchoReference>,
+        sample_rate: u32,
+        channels: usize,
+    ) -> Self {
+        let mut planner = RealFftPlanner::new();
+        let forward = planner.plan_fft_forward(2 * BLOCK);
+        let inverse = planner.plan_fft_inverse(2 * BLOCK);
+        let tail = (config.tail_ms as usize * sample_rate as usize / 1000).max(1);
+        let partitions = tail.div_ceil(BLOCK);
+        let bins = BLOCK + 1;
+        let scratch_len = forward.get_scratch_len().max(inverse.get_scratch_len());
+        Self {
+            reference,
+            channels,
+            history: (0..partitions)
+                .map(|_| vec![Complex::default(); bins])
+                .collect(),
+            history_peaks: (0..partitions).map(|_| 0.0).collect(),
+            last_reference: vec![0.0; BLOCK],
+            filters: (0..channels)
+                .map(|_| {
+                    (0..partitions)
+                        .map(|_| vec![Complex::default(); bins])
+                        .collect()
+                })
+                .collect(),
+            mic_in: Vec::with_capacity(BLOCK * channels),
+            reference_in: Vec::with_capacity(BLOCK),
+            out: std::iter::repeat_n(0.0, BLOCK * channels).collect(),
+            captured: 0,
+            capture_epoch: None,
+            reference_offset: None,
+            mic_power: 0.0,
+            out_power: 0.0,
+            forward,
+            inverse,
+            frame: vec![0.0; 2 * BLOCK],
+            spectrum: vec![Complex::default(); bins],
+            scratch: vec![Complex::default(); scratch_len],
+        }
+    }
+
+    /// The delay this adds, in frames.
+    pub fn latency_frames(&self) -> usize {
+        BLOCK
+    }
+
+    /// How much quieter the output is than the mic, in dB. This is roughly how much echo is being removed.
+    pub fn reduction_db(&self) -> f32 {
+        10.0 * (self.mic_power.max(1e-12) / self.out_power.max(1e-12)).log10()
+    }
+
+    /// Cancels echo in interleaved samples, the first of which was captured at `captured_at`.
+    pub fn process(&mut self, samples: &mut [f32], captured_at: Instant) {
+        let channels = self.channels;
+        let expected = self
+            .capture_epoch
+            .and_then(|epoch| follow(epoch + self.reference.duration(self.captured), captured_at));
+        // If capture stalled or jumped, start its clock over.
+        let first = expected.unwrap_or(captured_at);
+        let epoch = first - self.reference.duration(self.captured);
+        self.capture_epoch = Some(epoch);
+        for frame in samples.chunks_mut(channels) {
+            self.mic_in.extend_from_slice(frame);
+            self.captured += 1;
+            for sample in frame {
+                *sample = self.out.pop_front().unwrap_or(0.0);
+            }
+            if self.mic_in.len() == BLOCK * channels {
+                let block = self.captured - BLOCK as i64;
+                let heard_at = epoch + self.reference.duration(block) + LEAD;
+                if let Some(reference) = self.reference.index_at(heard_at) {
+                    let offset = reference - block;
+                    if self
+                        .reference_offset
+                        .is_none_or(|current| (current - offset).abs() > MAX_SLIP)
+                    {
+                        self.reference_offset = Some(offset);
+                    }
+                }
+                let index = self.reference_offset.map(|offset| block + offset);
+                self.process_block(index);
+                self.mic_in.clear();
+            }
+        }
+    }
+
+    fn process_block(&mut self, reference: Option<i64>) {
+        let channels = self.channels;
+        self.reference_in.resize(BLOCK, 0.0);
+        match reference {
+            Some(index) => self.reference.read(index, &mut self.reference_in),
+            None => self.reference_in.fill(0.0),
+        }
+
+        // Overlap-save: each spectrum covers the previous block and this one.
+        self.frame[..BLOCK].copy_from_slice(&self.last_reference);
+        self.frame[BLOCK..].copy_from_slice(&self.reference_in);
+        self.last_reference.copy_from_slice(&self.reference_in);
+        let mut newest = self
+            .history
+            .pop_back()
+            .expect("there's at least one partition");
+        self.forward
+            .process_with_scratch(&mut self.frame, &mut newest, &mut self.scratch)
+            .expect("buffers are the planned size");
+        self.history.push_front(newest);
+        self.history_peaks.pop_back();
+        self.history_peaks.push_front(
+            self.reference_in
+                .iter()
+                .fold(0.0f32, |peak, s| peak.max(s.abs())),
+        );
+        let reference_peak = self.history_peaks.iter().fold(0.0f32, |a, &b| a.max(b));
+
+        let bins = self.spectrum.len();
+        let mut norm = vec![1e-6f32; bins];
+        for spectrum in &self.history {
+            for (norm, bin) in norm.iter_mut().zip(spectrum) {
+                *norm += bin.norm_sqr();
+            }
+        }
+
+        let scale = 1.0 / (2 * BLOCK) as f32;
+        let mut output = vec![0.0f32; BLOCK * channels];
+        for channel in 0..channels {
+            // Estimate the echo, and take it away from what the mic heard.
+            self.spectrum.fill(Complex::default());
+            for (filter, reference) in self.filters[channel].iter().zip(&self.history) {
+                for ((out, w), x) in self.spectrum.iter_mut().zip(filter).zip(reference) {
+                    *out += w * x;
+                }
+            }
+            self.inverse_into_frame();
+            let mut mic_peak = 0.0f32;
+            let mut error = [0.0f32; BLOCK];
+            for (i, error) in error.iter_mut().enumerate() {
+                let mic = self.mic_in[i * channels + channel];
+                mic_peak = mic_peak.max(mic.abs());
+                *error = mic - self.frame[BLOCK + i] * scale;
+                output[i * channels + channel] = *error;
+                self.mic_power += (mic * mic - self.mic_power) * 0.001;
+                self.out_power += (*error * *error - self.out_power) * 0.001;
+            }
+
+            // If the mic is louder than the echo could be, someone's talking over it, so leave the filter alone.
+            let double_talk = mic_peak > 0.5 * reference_peak;
+            if double_talk || reference_peak < 1e-4 {
+                continue;
+            }
+            self.frame[..BLOCK].fill(0.0);
+            self.frame[BLOCK..].copy_from_slice(&error);
+            let mut error_spectrum = vec![Complex::default(); bins];
+            self.forward
+                .process_with_scratch(&mut self.frame, &mut error_spectrum, &mut self.scratch)
+                .expect("buffers are the planned size");
+            for partition in 0..self.history.len() {
+                for ((gradient, x), (e, norm)) in self
+                    .spectrum
+                    .iter_mut()
+                    .zip(&self.history[partition])
+                    .zip(error_spectrum.iter().zip(&norm))
+                {
+                    *gradient = x.conj() * e / *norm;
+                }
+                // Keep the update causal and no longer than a block, or it wraps around.
+                self.inverse_into_frame();
+                for sample in &mut self.frame[..BLOCK] {
+                    *sample *= scale;
+                }
+                self.frame[BLOCK..].fill(0.0);
+                self.forward
+                    .process_with_scratch(&mut self.frame, &mut self.spectrum, &mut self.scratch)
+                    .expect("buffers are the planned size");
+                for (w, g) in self.filters[channel][partition]
+                    .iter_mut()
+                    .zip(&self.spectrum)
+                {
+                    *w += g * STEP_SIZE;
+                }
+            }
+        }
+        self.out.extend(output);
+    }
+
+    /// Transforms `spectrum` back into `frame`, unscaled.
+    fn inverse_into_frame(&mut self) {
+        // These have to be real for the inverse transform.
+        self.spectrum[0].im = 0.0;
+        self.spectrum.last_mut().unwrap().im = 0.0;
+        self.inverse
+            .process_with_scratch(&mut self.spectrum, &mut self.frame, &mut self.scratch)
+            .expect("buffers are the planned size");
+    }
+}
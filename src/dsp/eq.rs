@@ -0,0 +1,127 @@
+use std::f32::consts::PI;
+
+use crate::config::global::FilterConfig;
+
+/// A chain of biquad filters, applied in order.
+pub struct Equalizer {
+    filters: Vec<Biquad>,
+}
+
+impl Equalizer {
+    pub fn new(config: &[FilterConfig], sample_rate: u32, channels: usize) -> Self {
+        Self {
+            filters: config
+                .iter()
+                .map(|filter| Biquad::new(filter, sample_rate, channels))
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Filters interleaved samples.
+    pub fn process(&mut self, samples: &mut [f32], channels: usize) {
+        for filter in &mut self.filters {
+            filter.process(samples, channels);
+        }
+    }
+}
+
+/// A second-order IIR filter, with coefficients from the Audio EQ Cookbook.
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    /// The transposed direct form II state for each channel.
+    state: Vec<[f32; 2]>,
+}
+
+impl Biquad {
+    fn new(config: &FilterConfig, sample_rate: u32, channels: usize) -> Self {
+        let nyquist = sample_rate as f32 / 2.0;
+        let mut frequency = config.frequency();
+        if frequency >= nyquist * 0.95 {
+            log::warn!(
+                "{frequency} Hz is too close to the Nyquist frequency at {sample_rate} Hz, using {} Hz",
+                nyquist * 0.95
+            );
+            frequency = nyquist * 0.95;
+        }
+        let w0 = 2.0 * PI * frequency.max(1.0) / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * config.q().max(0.01));
+        let gain = 10f32.powf(config.gain_db() / 40.0);
+        let [b0, b1, b2, a0, a1, a2] = match config {
+            FilterConfig::HighPass { .. } => [
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ],
+            FilterConfig::LowPass { .. } => [
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ],
+            FilterConfig::Peaking { .. } => [
+                1.0 + alpha * gain,
+                -2.0 * cos,
+                1.0 - alpha * gain,
+                1.0 + alpha / gain,
+                -2.0 * cos,
+                1.0 - alpha / gain,
+            ],
+            FilterConfig::LowShelf { .. } => {
+                let root = 2.0 * gain.sqrt() * alpha;
+                [
+                    gain * ((gain + 1.0) - (gain - 1.0) * cos + root),
+                    2.0 * gain * ((gain - 1.0) - (gain + 1.0) * cos),
+                    gain * ((gain + 1.0) - (gain - 1.0) * cos - root),
+                    (gain + 1.0) + (gain - 1.0) * cos + root,
+                    -2.0 * ((gain - 1.0) + (gain + 1.0) * cos),
+                    (gain + 1.0) + (gain - 1.0) * cos - root,
+                ]
+            }
+            FilterConfig::HighShelf { .. } => {
+                let root = 2.0 * gain.sqrt() * alpha;
+                [
+                    gain * ((gain + 1.0) + (gain - 1.0) * cos + root),
+                    -2.0 * gain * ((gain - 1.0) + (gain + 1.0) * cos),
+                    gain * ((gain + 1.0) + (gain - 1.0) * cos - root),
+                    (gain + 1.0) - (gain - 1.0) * cos + root,
+                    2.0 * ((gain - 1.0) - (gain + 1.0) * cos),
+                    (gain + 1.0) - (gain - 1.0) * cos - root,
+                ]
+            }
+        };
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            state: vec![[0.0; 2]; channels],
+        }
+    }
+
+    fn process(&mut self, samples: &mut [f32], channels: usize) {
+        for frame in samples.chunks_mut(channels) {
+            for (sample, state) in frame.iter_mut().zip(&mut self.state) {
+                let input = *sample;
+                let output = self.b0 * input + state[0];
+                state[0] = self.b1 * input - self.a1 * output + state[1];
+                state[1] = self.b2 * input - self.a2 * output;
+                *sample = output;
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use crate::pcm::{f32_to_s24le, s24le_to_f32};
+
+/// Fades 24-bit audio in when a stream starts, and out when it stops, so neither end pops.
+///
+/// Fading out needs the end of the audio before it's played, so the last fade's worth of each buffer is held back
+/// until the next one arrives.
+pub struct StreamFade {
+    /// The length of a fade, in bytes.
+    fade_bytes: usize,
+    held: Vec<u8>,
+    /// Whether the next audio starts a stream.
+    stopped: bool,
+}
+
+impl StreamFade {
+    pub fn new(fade_ms: u32, sample_rate: u32, channels: usize) -> Self {
+        let frames = (u64::from(sample_rate) * u64::from(fade_ms) / 1000) as usize;
+        Self {
+            fade_bytes: frames * channels * 3,
+            held: Vec::new(),
+            stopped: true,
+        }
+    }
+
+    /// Takes the next buffer of the stream, and returns what's ready to be played.
+    pub fn next(&mut self, buffer: &[u8]) -> Vec<u8> {
+        let mut audio = std::mem::take(&mut self.held);
+        let start = audio.len();
+        audio.extend_from_slice(buffer);
+        if self.stopped {
+            self.stopped = false;
+            ramp(&mut audio[start..], self.fade_bytes, |position| position);
+        }
+        let keep = self.fade_bytes.min(audio.len());
+        self.held = audio.split_off(audio.len() - keep);
+        audio
+    }
+
+    /// Ends the stream, returning the rest of it, faded out.
+    pub fn stop(&mut self) -> Vec<u8> {
+        self.stopped = true;
+        let mut audio = std::mem::take(&mut self.held);
+        let len = audio.len();
+        ramp(&mut audio, len, |position| 1.0 - position);
+        audio
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+}
+
+/// Scales the first `len` bytes of `audio` by `gain`, given how far through them each sample is, from 0 to 1.
+fn ramp(audio: &mut [u8], len: usize, gain: impl Fn(f32) -> f32) {
+    let len = len.min(audio.len());
+    if len == 0 {
+        return;
+    }
+    let mut samples = Vec::with_capacity(len / 3);
+    s24le_to_f32(&audio[..len], &mut samples);
+    let count = samples.len() as f32;
+    for (i, sample) in samples.iter_mut().enumerate() {
+        *sample *= gain(i as f32 / count);
+    }
+    let mut bytes = Vec::with_capacity(len);
+    f32_to_s24le(&samples, &mut bytes);
+    audio[..bytes.len()].copy_from_slice(&bytes);
+}
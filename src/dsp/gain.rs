@@ -0,0 +1,56 @@
+/// How long gain changes take, so muting or moving a fader doesn't click.
+const FADE_MS: u32 = 10;
+
+/// Scales audio, ramping smoothly whenever the target changes.
+pub struct Gain {
+    fade_frames: u32,
+    current: f32,
+    target: f32,
+    step: f32,
+    remaining: u32,
+}
+
+impl Gain {
+    /// Starts out at `initial`, a linear gain, so the first audio isn't faded from anywhere.
+    pub fn new(sample_rate: u32, initial: f32) -> Self {
+        Self {
+            fade_frames: (sample_rate * FADE_MS / 1000).max(1),
+            current: initial,
+            target: initial,
+            step: 0.0,
+            remaining: 0,
+        }
+    }
+
+    /// Starts fading towards `target`, a linear gain.
+    pub fn set_target(&mut self, target: f32) {
+        if target == self.target {
+            return;
+        }
+        self.target = target;
+        self.remaining = self.fade_frames;
+        self.step = (target - self.current) / self.fade_frames as f32;
+    }
+
+    /// Whether processing would leave the audio as it is.
+    pub fn is_unity(&self) -> bool {
+        self.remaining == 0 && self.current == 1.0
+    }
+
+    /// Applies the gain to interleaved samples.
+    pub fn process(&mut self, samples: &mut [f32], channels: usize) {
+        for frame in samples.chunks_mut(channels) {
+            if self.remaining > 0 {
+                self.remaining -= 1;
+                self.current = if self.remaining == 0 {
+                    self.target
+                } else {
+                    self.current + self.step
+                };
+            }
+            for sample in frame {
+                *sample *= self.current;
+            }
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use crate::config::global::GateConfig;
+
+/// Silences audio that stays under a threshold, opening again as soon as it's crossed.
+pub struct NoiseGate {
+    threshold: f32,
+    /// How much the gain moves each frame while opening and closing.
+    attack_step: f32,
+    release_step: f32,
+    hold_frames: u32,
+    /// Frames left before the gate starts closing.
+    held: u32,
+    gain: f32,
+}
+
+impl NoiseGate {
+    pub fn new(config: &GateConfig, sample_rate: u32) -> Self {
+        let frames = |ms: f32| (ms * sample_rate as f32 / 1000.0).max(1.0);
+        Self {
+            threshold: 10f32.powf(config.threshold_db / 20.0),
+            attack_step: 1.0 / frames(config.attack_ms),
+            release_step: 1.0 / frames(config.release_ms),
+            hold_frames: frames(config.hold_ms) as u32,
+            held: 0,
+            gain: 0.0,
+        }
+    }
+
+    /// Gates interleaved samples. Returns false if the gate was shut for all of them.
+    pub fn process(&mut self, samples: &mut [f32], channels: usize) -> bool {
+        let mut open = false;
+        for frame in samples.chunks_mut(channels) {
+            let level = frame.iter().fold(0.0f32, |level, s| level.max(s.abs()));
+            if level >= self.threshold {
+                self.held = self.hold_frames;
+            }
+            if self.held > 0 {
+                self.held -= 1;
+                self.gain = (self.gain + self.attack_step).min(1.0);
+            } else {
+                self.gain = (self.gain - self.release_step).max(0.0);
+            }
+            open |= self.gain > 0.0;
+            for sample in frame {
+                *sample *= self.gain;
+            }
+        }
+        open
+    }
+}
@@ -0,0 +1,26 @@
+/// Bends peaks over a threshold smoothly towards full scale, instead of letting them clip.
+pub struct SoftLimiter {
+    threshold: f32,
+}
+
+impl SoftLimiter {
+    pub fn new(threshold_db: f32) -> Self {
+        Self {
+            threshold: 10f32.powf(threshold_db.min(0.0) / 20.0),
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let threshold = self.threshold;
+        let headroom = 1.0 - threshold;
+        for sample in samples {
+            let magnitude = sample.abs();
+            if magnitude <= threshold {
+                continue;
+            }
+            // Matches the slope at the threshold, and never quite reaches 1.0.
+            let over = (magnitude - threshold) / headroom.max(f32::EPSILON);
+            *sample = (threshold + headroom * over.tanh()).copysign(*sample);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Combines audio captured from several sources into one stream.
+
+use thiserror::Error;
+
+use crate::config::global::{CaptureMixConfig, MixLayout};
+use crate::pcm::{f32_to_s24le, s24le_to_f32};
+
+#[derive(Debug, Error)]
+pub enum MixError {
+    #[error("At least one source is needed")]
+    NoSources,
+    #[error("Every source needs a channel count with the channels layout")]
+    MissingChannels,
+    #[error("A source can't have 0 channels")]
+    NoChannels,
+    #[error("Sources take up {sources} channels, but {format} are sent")]
+    ChannelMismatch { sources: u32, format: u8 },
+}
+
+/// Somewhere to capture from.
+pub struct MixSource {
+    /// Defaults to the capture device.
+    pub device: Option<String>,
+    /// How many channels to capture.
+    pub channels: u8,
+    gain: f32,
+}
+
+/// Mixes or lays out a packet from each source into one packet.
+pub struct SourceMixer {
+    layout: MixLayout,
+    sources: Vec<MixSource>,
+    channels: usize,
+    input: Vec<f32>,
+    output: Vec<f32>,
+}
+
+impl SourceMixer {
+    pub fn new(config: &CaptureMixConfig, channels: u8) -> Result<Self, MixError> {
+        if config.sources.is_empty() {
+            return Err(MixError::NoSources);
+        }
+        let sources = config
+            .sources
+            .iter()
+            .map(|source| {
+                let source_channels = match config.layout {
+                    MixLayout::Mix => channels,
+                    MixLayout::Channels => source.channels.ok_or(MixError::MissingChannels)?,
+                };
+                if source_channels == 0 {
+                    return Err(MixError::NoChannels);
+                }
+                Ok(MixSource {
+                    device: source.device.clone(),
+                    channels: source_channels,
+                    gain: 10f32.powf(source.gain_db / 20.0),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if matches!(config.layout, MixLayout::Channels) {
+            let total = sources.iter().map(|s| u32::from(s.channels)).sum();
+            if total != u32::from(channels) {
+                return Err(MixError::ChannelMismatch {
+                    sources: total,
+                    format: channels,
+                });
+            }
+        }
+        Ok(Self {
+            layout: config.layout,
+            sources,
+            channels: usize::from(channels),
+            input: Vec::new(),
+            output: Vec::new(),
+        })
+    }
+
+    pub fn sources(&self) -> &[MixSource] {
+        &self.sources
+    }
+
+    /// Combines 24-bit packets, one from each source in order, each the same number of frames long.
+    pub fn mix(&mut self, buffers: &[&[u8]], out: &mut Vec<u8>) {
+        let first = &self.sources[0];
+        let frames = buffers[0].len() / (usize::from(first.channels) * 3);
+        self.output.clear();
+        self.output.resize(frames * self.channels, 0.0);
+        let mut offset = 0;
+        for (source, buffer) in self.sources.iter().zip(buffers) {
+            let source_channels = usize::from(source.channels);
+            self.input.clear();
+            s24le_to_f32(buffer, &mut self.input);
+            let input = self.input.chunks_exact(source_channels).take(frames);
+            for (output, input) in self.output.chunks_exact_mut(self.channels).zip(input) {
+                let output = match self.layout {
+                    MixLayout::Mix => output,
+                    MixLayout::Channels => &mut output[offset..offset + source_channels],
+                };
+                for (output, input) in output.iter_mut().zip(input) {
+                    *output += input * source.gain;
+                }
+            }
+            offset += source_channels;
+        }
+        out.clear();
+        f32_to_s24le(&self.output, out);
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use crate::config::global::SidetoneConfig;
+use crate::control::protocol::Direction;
+use crate::control::Controls;
+use crate::dsp::gain::Gain;
+use crate::pcm::{f32_to_s24le, s24le_to_f32};
+
+/// Turns a copy of what the mic picked up into what we let the user hear of themselves.
+pub struct Sidetone {
+    controls: Arc<Controls>,
+    level: f32,
+    gain: Gain,
+    channels: usize,
+    samples: Vec<f32>,
+}
+
+impl Sidetone {
+    pub fn new(
+        config: &SidetoneConfig,
+        controls: Arc<Controls>,
+        sample_rate: u32,
+        channels: usize,
+    ) -> Self {
+        let level = 10f32.powf(config.gain_db / 20.0);
+        let muted = controls.direction(Direction::Tx).muted();
+        Self {
+            controls,
+            level,
+            gain: Gain::new(sample_rate, if muted { 0.0 } else { level }),
+            channels,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Scales 24-bit samples into sidetone. It's silent while captured audio is muted, so nobody thinks they're heard
+    /// when they aren't.
+    pub fn process(&mut self, buffer: &[u8]) -> Vec<u8> {
+        let muted = self.controls.direction(Direction::Tx).muted();
+        self.gain.set_target(if muted { 0.0 } else { self.level });
+        self.samples.clear();
+        s24le_to_f32(buffer, &mut self.samples);
+        self.gain.process(&mut self.samples, self.channels);
+        let mut out = Vec::with_capacity(buffer.len());
+        f32_to_s24le(&self.samples, &mut out);
+        out
+    }
+}
@@ -0,0 +1,173 @@
+//! Mixes several received streams into one, each through a jitter buffer of its own.
+
+use std::collections::VecDeque;
+
+use crate::audio_engine::{Audio, AudioFormat};
+use crate::config::global::ReceiveStreamConfig;
+use crate::pcm::s24le_to_f32;
+
+/// One received stream's place in the mix.
+struct MixedStream {
+    name: String,
+    gain: f32,
+    pan: f32,
+    /// How many frames to buffer before starting to play.
+    jitter_frames: usize,
+    format: Option<AudioFormat>,
+    /// Audio waiting to be mixed, already in the output's channels, with gain and pan applied.
+    buffer: VecDeque<f32>,
+    /// Whether the buffer has filled up and is being played from.
+    playing: bool,
+    /// Whether the sender's gate shut, so running dry is expected.
+    idle: bool,
+    samples: Vec<f32>,
+}
+
+impl MixedStream {
+    /// Adds 24-bit audio to the buffer, mapped to `channels` output channels.
+    fn push(&mut self, bytes: &[u8], rate: u32, channels: usize) {
+        let Some(format) = self.format else {
+            return;
+        };
+        // There's no resampling, so other rates can't be mixed in.
+        if format.rate != rate {
+            return;
+        }
+        self.idle = false;
+        self.samples.clear();
+        s24le_to_f32(bytes, &mut self.samples);
+        let source_channels = usize::from(format.channels);
+        // Balance, so a centred stream keeps its level on both sides.
+        let left = self.gain * (1.0 - self.pan).min(1.0);
+        let right = self.gain * (1.0 + self.pan).min(1.0);
+        for frame in self.samples.chunks_exact(source_channels) {
+            if channels == 2 {
+                self.buffer.push_back(frame[0] * left);
+                self.buffer.push_back(frame[1 % source_channels] * right);
+            } else if channels == 1 {
+                let sum: f32 = frame.iter().sum();
+                self.buffer
+                    .push_back(sum / source_channels as f32 * self.gain);
+            } else {
+                for channel in 0..channels {
+                    self.buffer
+                        .push_back(frame[channel % source_channels] * self.gain);
+                }
+            }
+        }
+    }
+}
+
+/// Buffers each received stream and mixes them together, at one rate and channel count.
+pub struct StreamMixer {
+    streams: Vec<MixedStream>,
+    rate: u32,
+    channels: usize,
+    fade_frames: usize,
+}
+
+impl StreamMixer {
+    /// Mixes `streams` into audio in `format`, fading each in and out over `fade_ms` when it starts and stops.
+    pub fn new(streams: &[ReceiveStreamConfig], format: AudioFormat, fade_ms: u32) -> Self {
+        let streams = streams
+            .iter()
+            .map(|stream| MixedStream {
+                name: stream.stream_name.to_string(),
+                gain: 10f32.powf(stream.gain_db / 20.0),
+                pan: stream.pan.clamp(-1.0, 1.0),
+                jitter_frames: (u64::from(format.rate) * u64::from(stream.jitter_ms) / 1000)
+                    as usize,
+                format: None,
+                buffer: VecDeque::new(),
+                playing: false,
+                idle: true,
+                samples: Vec::new(),
+            })
+            .collect();
+        Self {
+            streams,
+            rate: format.rate,
+            channels: usize::from(format.channels),
+            fade_frames: ((u64::from(format.rate) * u64::from(fade_ms) / 1000) as usize).max(1),
+        }
+    }
+
+    /// Takes what arrived for the stream at `index`.
+    pub fn push(&mut self, index: usize, audio: Audio) {
+        let stream = &mut self.streams[index];
+        match audio {
+            Audio::Samples(bytes) => stream.push(&bytes, self.rate, self.channels),
+            Audio::Idle => stream.idle = true,
+            Audio::Format(format) => {
+                if format.rate != self.rate {
+                    log::warn!(
+                        "Can't mix {} while it's {}, only {} Hz",
+                        stream.name,
+                        format,
+                        self.rate
+                    );
+                }
+                stream.format = Some(format);
+            }
+        }
+    }
+
+    /// Mixes the next `frames` frames into `out`. Returns how many streams ran dry while they should have been
+    /// playing.
+    pub fn next(&mut self, frames: usize, out: &mut Vec<f32>) -> u32 {
+        let channels = self.channels;
+        out.clear();
+        out.resize(frames * channels, 0.0);
+        let mut underruns = 0;
+        for stream in &mut self.streams {
+            let waiting = stream.buffer.len() / channels;
+            if !stream.playing {
+                if waiting < stream.jitter_frames.max(frames) {
+                    continue;
+                }
+                stream.playing = true;
+                // Fade in, so starting partway through a sound doesn't pop.
+                for (index, sample) in stream
+                    .buffer
+                    .iter_mut()
+                    .take(self.fade_frames * channels)
+                    .enumerate()
+                {
+                    *sample *= (index / channels) as f32 / self.fade_frames as f32;
+                }
+            }
+            // Skip ahead if the sender's clock is running fast, or a burst arrived late.
+            let max_waiting = stream.jitter_frames * 2 + frames;
+            if waiting > max_waiting {
+                let skip = (waiting - stream.jitter_frames - frames) * channels;
+                stream.buffer.drain(..skip);
+            }
+            let available = (stream.buffer.len() / channels).min(frames);
+            let ending = available < frames;
+            // Fade out what's left, so running dry doesn't pop.
+            let fade = if ending {
+                available.min(self.fade_frames)
+            } else {
+                0
+            };
+            for (frame, output) in out.chunks_exact_mut(channels).take(available).enumerate() {
+                let scale = match (available - frame).checked_sub(1) {
+                    Some(left) if left < fade => left as f32 / fade as f32,
+                    _ => 1.0,
+                };
+                for output in output {
+                    *output += stream.buffer.pop_front().unwrap_or(0.0) * scale;
+                }
+            }
+            if ending {
+                if !stream.idle {
+                    underruns += 1;
+                }
+                log::debug!("{} ran dry, buffering again", stream.name);
+                stream.playing = false;
+                stream.buffer.clear();
+            }
+        }
+        underruns
+    }
+}
@@ -0,0 +1,163 @@
+//! Things that happen to the stream, which can run configured commands.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::audio_engine::{has_media_role, MEDIA_ROLE_VAR};
+use crate::config::global::HooksConfig;
+use crate::ratelimit::RateLimit;
+
+/// How often each event's hook can run, so a burst of events doesn't start a burst of processes.
+const HOOK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub enum Event {
+    /// Audio started arriving from a peer.
+    PeerAppeared {
+        stream: String,
+        address: SocketAddr,
+        format: String,
+    },
+    /// Nothing has arrived from the peer for a while.
+    PeerSilent { stream: String, address: SocketAddr },
+    /// The peer started sending a different format.
+    FormatChanged {
+        stream: String,
+        address: SocketAddr,
+        previous: String,
+        format: String,
+    },
+    /// A packet failed decryption or replay checks.
+    AuthFailure {
+        stream: String,
+        address: SocketAddr,
+        reason: String,
+    },
+    /// The service is restarting.
+    Restart { reason: String },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::PeerAppeared { .. } => "peer_appeared",
+            Event::PeerSilent { .. } => "peer_silent",
+            Event::FormatChanged { .. } => "format_changed",
+            Event::AuthFailure { .. } => "auth_failure",
+            Event::Restart { .. } => "restart",
+        }
+    }
+
+    /// The environment variables that describe this to a hook.
+    fn environment(&self) -> Vec<(&'static str, String)> {
+        let mut environment = vec![("AUDIO_BICYCLE_EVENT", self.name().to_string())];
+        match self {
+            Event::PeerAppeared {
+                stream,
+                address,
+                format,
+            } => {
+                environment.push(("AUDIO_BICYCLE_STREAM", stream.clone()));
+                environment.push(("AUDIO_BICYCLE_PEER", address.to_string()));
+                environment.push(("AUDIO_BICYCLE_FORMAT", format.clone()));
+            }
+            Event::PeerSilent { stream, address } => {
+                environment.push(("AUDIO_BICYCLE_STREAM", stream.clone()));
+                environment.push(("AUDIO_BICYCLE_PEER", address.to_string()));
+            }
+            Event::FormatChanged {
+                stream,
+                address,
+                previous,
+                format,
+            } => {
+                environment.push(("AUDIO_BICYCLE_STREAM", stream.clone()));
+                environment.push(("AUDIO_BICYCLE_PEER", address.to_string()));
+                environment.push(("AUDIO_BICYCLE_PREVIOUS_FORMAT", previous.clone()));
+                environment.push(("AUDIO_BICYCLE_FORMAT", format.clone()));
+            }
+            Event::AuthFailure {
+                stream,
+                address,
+                reason,
+            } => {
+                environment.push(("AUDIO_BICYCLE_STREAM", stream.clone()));
+                environment.push(("AUDIO_BICYCLE_PEER", address.to_string()));
+                environment.push(("AUDIO_BICYCLE_REASON", reason.clone()));
+            }
+            Event::Restart { reason } => {
+                environment.push(("AUDIO_BICYCLE_REASON", reason.clone()));
+            }
+        }
+        environment
+    }
+}
+
+/// Runs the configured hook for each event. This lives for the whole process, so restarts can be reported too.
+#[derive(Default)]
+pub struct Events {
+    hooks: Mutex<HooksConfig>,
+    limits: Mutex<HashMap<&'static str, RateLimit>>,
+}
+
+impl Events {
+    /// Switches to the hooks from a newly loaded config.
+    pub fn configure(&self, hooks: &HooksConfig) {
+        *self.hooks.lock().unwrap() = hooks.clone();
+    }
+
+    /// Runs the hook for `event` in the background, if there is one.
+    pub fn emit(&self, event: Event) {
+        let hooks = self.hooks.lock().unwrap();
+        let command = match &event {
+            Event::PeerAppeared { .. } => &hooks.peer_appeared,
+            Event::PeerSilent { .. } => &hooks.peer_silent,
+            Event::FormatChanged { .. } => &hooks.format_changed,
+            Event::AuthFailure { .. } => &hooks.auth_failure,
+            Event::Restart { .. } => &hooks.restart,
+        };
+        let Some(command) = command.clone() else {
+            return;
+        };
+        let name = event.name();
+        let mut limits = self.limits.lock().unwrap();
+        let limit = limits
+            .entry(name)
+            .or_insert_with(|| RateLimit::new(HOOK_INTERVAL));
+        match limit.check() {
+            Some(0) => {}
+            Some(skipped) => log::warn!(
+                "Skipped the {} hook {} times, it ran too often",
+                name,
+                skipped
+            ),
+            None => return,
+        }
+        drop(limits);
+        let mut hook = tokio::process::Command::new("sh");
+        hook.arg("-c")
+            .arg(&command)
+            .envs(event.environment())
+            .stdin(std::process::Stdio::null());
+        if has_media_role() {
+            // That's meant for our own streams, not whatever the hook plays.
+            hook.env_remove(MEDIA_ROLE_VAR);
+        }
+        let mut child = match hook.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                log::warn!("Failed to run {} hook `{}`: {}", name, command, e);
+                return;
+            }
+        };
+        // Hooks can take as long as they like, and shouldn't be cut short by a restart.
+        tokio::spawn(async move {
+            match child.wait().await {
+                Ok(status) if status.success() => {}
+                Ok(status) => log::warn!("{} hook `{}` failed: {}", name, command, status),
+                Err(e) => log::warn!("Failed to wait for {} hook `{}`: {}", name, command, e),
+            }
+        });
+    }
+}
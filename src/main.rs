@@ -1,44 +1,136 @@
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
 use std::process::ExitCode;
 use std::process::Termination;
 use std::sync::Arc;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use futures::{select, FutureExt};
 use libpulse_binding::error::PAErr;
 use log::LevelFilter;
 use thiserror::Error;
-use tokio::net::UdpSocket;
+use tokio::net::{TcpListener, UdpSocket, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
 
+use crate::audio_engine::{Audio, AudioEngine, AudioFormat, PlaybackOutput};
 use crate::backoff::BackOff;
-use crate::config::global::{load_config, ConfigError};
-use crate::vban::receiver::ReceiverError;
-use crate::vban::transmitter::TransmitterError;
+use crate::config::global::{load_config, ConfigError, GateConfig};
+use crate::control::client::ClientError;
+use crate::control::protocol::{Direction, Request, Status};
+use crate::control::server::ServerState;
+use crate::control::Controls;
+use crate::dsp::echo::EchoReference;
+use crate::dsp::mix::{MixError, SourceMixer};
+use crate::dsp::sidetone::Sidetone;
+use crate::dsp::stream_mix::StreamMixer;
+use crate::dsp::Pipeline;
+use crate::events::{Event, Events};
+use crate::metrics::Labels;
+use crate::osc::server::OscServer;
+use crate::stats::{Gauge, Stats};
+use crate::task::AbortOnDrop;
+use crate::vban::crypto::{load_key, KeyFileError, Sealer, SessionStore, ENVELOPE_OVERHEAD};
+use crate::vban::fec::{ParityEncoder, PARITY_OVERHEAD};
+use crate::vban::opus::{OpusEncoder, OpusError};
+use crate::vban::receiver::{ReceiveStream, ReceiverError};
+use crate::vban::transmitter::{FormatError, TransmitterError, TxFormat, MAX_DATA_PACKET_SIZE};
+use crate::virtual_devices::{VirtualDeviceError, VirtualDevices};
 
 mod asciistackstr;
 mod audio_engine;
 mod backoff;
 mod config;
+mod control;
+mod dsp;
+mod events;
+mod metrics;
+mod monitor;
+mod osc;
+mod pcm;
+mod ratelimit;
+mod recent_log;
+mod stats;
+mod task;
 mod vban;
+mod virtual_devices;
 
 /// Service designed to run on systemd to connect to a VBAN stream pair for mic and sound output.
 #[derive(Parser)]
 pub struct AudioBicycle {
     #[clap(short, long, action = clap::ArgAction::Count)]
     pub verbose: u8,
+    /// How often to log link statistics, in seconds. 0 turns this off.
+    #[clap(long, default_value_t = 60)]
+    pub stats_interval: u64,
+    /// Talks to the running service instead of starting one.
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Shows the peer, formats, and link statistics.
+    Status {
+        /// Prints the raw JSON response.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Silences a direction.
+    Mute { direction: Direction },
+    /// Stops silencing a direction.
+    Unmute { direction: Direction },
+    /// Sets the gain of a direction, in dB.
+    Gain {
+        direction: Direction,
+        #[clap(allow_negative_numbers = true)]
+        db: f32,
+    },
+    /// Turns the soft limiter of a direction on or off.
+    Limiter {
+        direction: Direction,
+        #[clap(action = clap::ArgAction::Set, value_parser = clap::builder::BoolishValueParser::new())]
+        enabled: bool,
+    },
+    /// Turns noise suppression of captured audio on or off.
+    Denoise {
+        #[clap(action = clap::ArgAction::Set, value_parser = clap::builder::BoolishValueParser::new())]
+        enabled: bool,
+    },
+    /// Restarts the audio and network tasks.
+    Restart,
+    /// Opens a dashboard with meters, link health, and the recent log.
+    Monitor,
 }
 
 #[derive(Debug, Error)]
 enum AudioBicycleError {
     #[error("Couldn't load config: {0}")]
     Config(#[from] ConfigError),
+    #[error("Couldn't load key: {0}")]
+    Key(#[from] KeyFileError),
+    #[error("Couldn't set up the audio format: {0}")]
+    Format(#[from] FormatError),
+    #[error("Couldn't set up the capture mix: {0}")]
+    Mix(#[from] MixError),
+    #[error("Couldn't set up Opus: {0}")]
+    Opus(#[from] OpusError),
     #[error("PulseAudio error: {0}")]
     PulseAudio(#[from] PAErr),
+    #[error("Couldn't set up virtual devices: {0}")]
+    VirtualDevices(#[from] VirtualDeviceError),
     #[error("Couldn't create socket: {0}")]
     Socket(#[from] std::io::Error),
     #[error("Couldn't receive audio: {0}")]
     Receiver(#[from] ReceiverError),
     #[error("Couldn't transmit audio: {0}")]
     Transmitter(#[from] TransmitterError),
+    #[error("{0}")]
+    Control(#[from] ClientError),
+    #[error("Terminal error: {0}")]
+    Terminal(#[source] std::io::Error),
+    #[error("Restart requested")]
+    RestartRequested,
 }
 
 impl Termination for AudioBicycleError {
@@ -48,27 +140,96 @@ impl Termination for AudioBicycleError {
     }
 }
 
-#[tokio::main]
-async fn main() -> ExitCode {
+fn main() -> ExitCode {
     let args: AudioBicycle = AudioBicycle::parse();
-    env_logger::Builder::new()
-        .filter_level(match args.verbose {
-            0 => LevelFilter::Info,
-            1 => LevelFilter::Debug,
-            _ => LevelFilter::Trace,
-        })
-        .init();
+    recent_log::init(
+        env_logger::Builder::new()
+            .filter_level(match args.verbose {
+                0 => LevelFilter::Info,
+                1 => LevelFilter::Debug,
+                _ => LevelFilter::Trace,
+            })
+            .build(),
+    );
+    if args.command.is_none() {
+        // If the config can't be loaded, starting the service will say why.
+        let media_role = load_config()
+            .ok()
+            .and_then(|config| config.pulse.media_role);
+        audio_engine::set_media_role(media_role);
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("should be able to start the runtime")
+        .block_on(run(args))
+}
+
+async fn run(args: AudioBicycle) -> ExitCode {
+    if let Some(command) = args.command {
+        return match run_command(command).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                log::error!("{:#}", e);
+                e.report()
+            }
+        };
+    }
 
+    let stats = Arc::new(Stats::default());
+    let controls = Arc::new(Controls::default());
+    let events = Arc::new(Events::default());
+    if args.stats_interval > 0 {
+        tokio::spawn(Arc::clone(&stats).log_periodically(Duration::from_secs(args.stats_interval)));
+    }
+
+    // Kept across restarts, so applications using them aren't moved elsewhere. Dropping this removes them.
+    let mut virtual_devices = None;
+    // Also kept across restarts, so a restart doesn't let an old encrypted session be replayed.
+    let sessions = Arc::new(SessionStore::default());
+    let mut shutdown = Box::pin(shutdown_requested()).fuse();
     let mut backoff = BackOff::default();
     loop {
-        match main_for_result().await {
+        let result = select! {
+            result = main_for_result(
+                Arc::clone(&stats),
+                Arc::clone(&controls),
+                Arc::clone(&events),
+                &mut virtual_devices,
+                &sessions,
+            )
+            .fuse() => result,
+            _ = shutdown => {
+                log::info!("Shutting down");
+                break ExitCode::SUCCESS;
+            }
+        };
+        match result {
             Ok(_) => {
                 break ExitCode::SUCCESS;
             }
+            Err(AudioBicycleError::RestartRequested) => {
+                log::info!("Restarting service on request");
+                stats.restarts.inc();
+                events.emit(Event::Restart {
+                    reason: "requested".to_string(),
+                });
+            }
             Err(e) => {
                 if is_restartable_error(&e) {
                     log::warn!("Restarting service due to error: {:#}", e);
-                    backoff.back_off().await;
+                    stats.restarts.inc();
+                    events.emit(Event::Restart {
+                        reason: format!("{:#}", e),
+                    });
+                    select! {
+                        _ = backoff.back_off().fuse() => {}
+                        _ = shutdown => {
+                            log::info!("Shutting down");
+                            break ExitCode::SUCCESS;
+                        }
+                    }
                 } else {
                     log::error!("Exiting service due to error: {:#}", e);
                     break e.report();
@@ -78,6 +239,22 @@ async fn main() -> ExitCode {
     }
 }
 
+/// Waits for Ctrl-C or SIGTERM, so we can clean up before exiting.
+async fn shutdown_requested() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            log::warn!("Couldn't listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    select! {
+        _ = Box::pin(tokio::signal::ctrl_c()).fuse() => {}
+        _ = Box::pin(terminate.recv()).fuse() => {}
+    }
+}
+
 /// Is this error one that can be potentially handled by simply restarting the loop?
 fn is_restartable_error(err: &AudioBicycleError) -> bool {
     matches!(
@@ -86,39 +263,396 @@ fn is_restartable_error(err: &AudioBicycleError) -> bool {
             | AudioBicycleError::Receiver(ReceiverError::AudioChannelBroken)
             | AudioBicycleError::Transmitter(TransmitterError::SocketWrite(_))
             | AudioBicycleError::PulseAudio(_)
+            | AudioBicycleError::VirtualDevices(_)
     )
 }
 
-async fn main_for_result() -> Result<(), AudioBicycleError> {
+async fn run_command(command: Command) -> Result<(), AudioBicycleError> {
     let config = load_config()?;
+    let socket_path = config.control_socket()?;
+    let request = match command {
+        Command::Status { .. } => Request::Status,
+        Command::Mute { direction } => Request::Mute {
+            direction,
+            muted: true,
+        },
+        Command::Unmute { direction } => Request::Mute {
+            direction,
+            muted: false,
+        },
+        Command::Gain { direction, db } => Request::Gain { direction, db },
+        Command::Limiter { direction, enabled } => Request::Limiter { direction, enabled },
+        Command::Denoise { enabled } => Request::Denoise { enabled },
+        Command::Restart => Request::Restart,
+        Command::Monitor => {
+            return monitor::run(&socket_path)
+                .await
+                .map_err(AudioBicycleError::Terminal);
+        }
+    };
+    let response = control::client::send(&socket_path, &request).await?;
+    if let (Command::Status { json }, Some(status)) = (command, response.status) {
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&status).expect("status always serializes")
+            );
+        } else {
+            print_status(&status);
+        }
+    }
+    Ok(())
+}
+
+fn print_status(status: &Status) {
+    for stream in &status.stats.streams {
+        match &stream.peer {
+            Some(peer) => println!(
+                "{}: from {} ({}), last packet {:.1} s ago, jitter {:.2} ms",
+                stream.name,
+                peer.address,
+                peer.format,
+                peer.seconds_since_last_packet,
+                stream.jitter * 1000.0
+            ),
+            None => println!("{}: nothing received yet", stream.name),
+        }
+        if stream.playback_latency > 0.0 {
+            println!(
+                "{}: playback {:.1} ms on its own sink",
+                stream.name,
+                stream.playback_latency * 1000.0
+            );
+        }
+    }
+    for (name, direction) in [("Send", &status.tx), ("Receive", &status.rx)] {
+        println!(
+            "{}: {}, gain {} dB, limiter {}, format {}",
+            name,
+            if direction.muted { "muted" } else { "unmuted" },
+            direction.gain_db,
+            if direction.limiter { "on" } else { "off" },
+            direction.format.as_deref().unwrap_or("unknown")
+        );
+    }
+    println!(
+        "Noise suppression: {}",
+        if status.denoise { "on" } else { "off" }
+    );
+    let stats = &status.stats;
+    println!(
+        "Latency: playback {:.1} ms, capture {:.1} ms",
+        stats.playback_latency * 1000.0,
+        stats.capture_latency * 1000.0
+    );
+    println!(
+        "Packets: sent {}, received {}, lost {}, recovered {}, late {}, duplicate {}",
+        stats.packets_sent,
+        stats.packets_received,
+        stats.frames_lost,
+        stats.frames_recovered,
+        stats.frames_late,
+        stats.frames_duplicate
+    );
+    println!(
+        "Errors: rejected {}, auth failures {}, decode failures {}, underruns {}, overruns {}, restarts {}",
+        stats.rejected,
+        stats.auth_failures,
+        stats.decode_failures,
+        stats.underruns,
+        stats.overruns,
+        stats.restarts
+    );
+}
+
+/// Listens on `path`, replacing any socket left behind by a previous run. Only this user may connect.
+fn bind_control_socket(path: &Path) -> std::io::Result<UnixListener> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path.display()),
+            ));
+        }
+        Ok(_) => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another instance", path.display()),
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+async fn main_for_result(
+    stats: Arc<Stats>,
+    controls: Arc<Controls>,
+    events: Arc<Events>,
+    virtual_devices: &mut Option<VirtualDevices>,
+    sessions: &Arc<SessionStore>,
+) -> Result<(), AudioBicycleError> {
+    // Listen from the start, so a restart asked for while this run is starting up isn't missed.
+    let mut restart = Box::pin(controls.restart_requested()).fuse();
+    let config = load_config()?;
+    controls.initialize(&config);
+    events.configure(&config.events.hooks);
+    VirtualDevices::ensure(virtual_devices, &config.pulse, &config.stream_name)?;
+    let mut pulse = config.pulse.clone();
+    if let Some(devices) = virtual_devices {
+        pulse.capture.device = Some(devices.capture_device());
+        pulse.playback.device = Some(devices.playback_device());
+    }
+    let key = config
+        .encryption
+        .as_ref()
+        .map(|encryption| load_key(&encryption.key_file))
+        .transpose()?;
+    if let Some(encryption) = &config.encryption {
+        sessions.use_file(&encryption.session_file()?);
+    }
+    let mut overhead = 0;
+    if key.is_some() {
+        overhead += ENVELOPE_OVERHEAD;
+    }
+    if config.fec.is_some() {
+        // Parity packets hold a whole payload, plus their own header.
+        overhead += PARITY_OVERHEAD;
+    }
+    let tx_format = TxFormat::new(&config.format, overhead)?;
+    let format = tx_format.audio;
+    let mixer = config
+        .capture_mix
+        .as_ref()
+        .map(|mix| SourceMixer::new(mix, format.channels))
+        .transpose()?;
+    let opus = config
+        .opus
+        .as_ref()
+        .map(|opus| OpusEncoder::new(opus, format, (MAX_DATA_PACKET_SIZE - overhead) as usize))
+        .transpose()?;
 
     let socket = UdpSocket::bind(config.local_address).await?;
     let socket = Arc::new(socket);
 
-    let (pa_out_send, pa_out_recv) = tokio::sync::mpsc::channel::<Vec<u8>>(10);
-    let (pa_in_send, pa_in_recv) = tokio::sync::mpsc::channel::<Vec<u8>>(10);
+    let _metrics_task = match &config.metrics {
+        Some(metrics) => {
+            let listener = TcpListener::bind(metrics.listen_address).await?;
+            let labels = Labels {
+                stream_name: config.stream_name.to_string(),
+                peer: config.dest_address.to_string(),
+            };
+            Some(AbortOnDrop::spawn(metrics::serve(
+                listener,
+                Arc::clone(&stats),
+                labels,
+            )))
+        }
+        None => None,
+    };
+
+    let _osc_task = match &config.osc {
+        Some(osc) => {
+            let server = OscServer {
+                socket: UdpSocket::bind(osc.listen_address).await?,
+                controls: Arc::clone(&controls),
+                stats: Arc::clone(&stats),
+                meter_interval: Duration::from_millis(osc.meter_interval_ms.max(1)),
+                subscribe_from: osc.subscribe_from.clone(),
+            };
+            Some(AbortOnDrop::spawn(server.run()))
+        }
+        None => None,
+    };
+
+    let codec = if opus.is_some() { "Opus" } else { "PCM" };
+    let _control_task = AbortOnDrop::spawn(control::server::serve(
+        bind_control_socket(&config.control_socket()?)?,
+        ServerState {
+            controls: Arc::clone(&controls),
+            stats: Arc::clone(&stats),
+            tx_format: format!("{}, {}", tx_format, codec),
+        },
+    ));
+
+    let mut capture = Pipeline::new(
+        Arc::clone(&controls),
+        Direction::Tx,
+        &config.capture,
+        format.rate,
+        usize::from(format.channels),
+    );
+    let mut echo_reference = None;
+    if let Some(aec) = &config.aec {
+        let reference = Arc::new(EchoReference::new(format.rate));
+        capture = capture.with_echo_canceller(aec, Arc::clone(&reference));
+        echo_reference = Some(reference);
+    }
+    if let Some(gate) = &config.gate {
+        capture = capture.with_gate(gate);
+    }
+    if let Some(denoise) = &config.denoise {
+        capture = capture.with_denoiser(denoise);
+    }
+    if let Some(agc) = &config.agc {
+        capture = capture.with_agc(agc);
+    }
+    let mut duck = None;
+    if let Some(config) = &config.duck {
+        let key = Arc::new(Gauge::default());
+        capture = capture.with_duck_key(Arc::clone(&key));
+        duck = Some((config.clone(), key));
+    }
+    let playback_controls = Arc::clone(&controls);
+    let playback_config = config.playback.clone();
+    let playback = Arc::new(move |format: AudioFormat| {
+        let playback = Pipeline::new(
+            Arc::clone(&playback_controls),
+            Direction::Rx,
+            &playback_config,
+            format.rate,
+            usize::from(format.channels),
+        );
+        match &duck {
+            Some((duck, key)) => playback.with_ducker(duck, Arc::clone(key)),
+            None => playback,
+        }
+    });
+
+    // Each received stream gets its own queue, and is played on its own or mixed with the others.
+    let mut streams = Vec::new();
+    let mut outputs = Vec::new();
+    if config.receive.is_empty() {
+        let (send, recv) = tokio::sync::mpsc::channel::<Audio>(10);
+        let stream_stats = stats
+            .receive_streams([config.stream_name.as_str()])
+            .remove(0);
+        streams.push(ReceiveStream::new(
+            config.stream_name.clone(),
+            None,
+            send,
+            key.as_ref()
+                .map(|key| sessions.opener(key, &config.stream_name)),
+            Arc::clone(&stats),
+            stream_stats,
+        ));
+        outputs.push(PlaybackOutput::Direct { audio: recv });
+    } else {
+        let mut mixed = Vec::new();
+        let mut mixed_audio = Vec::new();
+        let all_stats = stats.receive_streams(
+            config
+                .receive
+                .iter()
+                .map(|stream| stream.stream_name.as_str()),
+        );
+        for (stream, stream_stats) in config.receive.iter().zip(all_stats) {
+            let (send, recv) = tokio::sync::mpsc::channel::<Audio>(10);
+            streams.push(ReceiveStream::new(
+                stream.stream_name.clone(),
+                stream.from,
+                send,
+                key.as_ref()
+                    .map(|key| sessions.opener(key, &stream.stream_name)),
+                Arc::clone(&stats),
+                Arc::clone(&stream_stats),
+            ));
+            match &stream.sink {
+                // On its own, but still through a jitter buffer, with its gain and pan.
+                Some(sink) => outputs.push(PlaybackOutput::Mixed {
+                    sink: Some((sink.clone(), stream_stats)),
+                    mixer: StreamMixer::new(
+                        std::slice::from_ref(stream),
+                        format,
+                        config.stream_fade.fade_ms,
+                    ),
+                    audio: vec![recv],
+                }),
+                None => {
+                    mixed.push(stream.clone());
+                    mixed_audio.push(recv);
+                }
+            }
+        }
+        if !mixed.is_empty() {
+            outputs.push(PlaybackOutput::Mixed {
+                sink: None,
+                mixer: StreamMixer::new(&mixed, format, config.stream_fade.fade_ms),
+                audio: mixed_audio,
+            });
+        }
+    }
+    let (pa_in_send, pa_in_recv) = tokio::sync::mpsc::channel::<Audio>(10);
 
-    let mut pa_thread = tokio::task::spawn(audio_engine::run(pa_out_recv, pa_in_send)).fuse();
+    let engine = AudioEngine {
+        format,
+        packet_size: tx_format.capture_size(),
+        stats: Arc::clone(&stats),
+        capture,
+        playback,
+        echo_reference,
+        mixer,
+        stream_fade: config.stream_fade.clone(),
+        pulse,
+        sidetone: config.sidetone.as_ref().map(|sidetone| {
+            Sidetone::new(
+                sidetone,
+                Arc::clone(&controls),
+                format.rate,
+                usize::from(format.channels),
+            )
+        }),
+    };
+    let mut pa_thread = AbortOnDrop::spawn(engine.run(outputs, pa_in_send)).fuse();
     let receiver = vban::receiver::Receiver {
-        stream_name: config.stream_name.clone(),
-        recv_address: config.dest_address.ip(),
-        audio_out: pa_out_send,
+        streams,
+        source_policy: config.source_policy(),
         socket: Arc::clone(&socket),
+        stats: Arc::clone(&stats),
+        events,
+        peer_timeout: Duration::from_millis(config.events.peer_timeout_ms.max(1)),
     };
-    let mut receiver_thread = tokio::task::spawn(receiver.run()).fuse();
+    let mut receiver_thread = AbortOnDrop::spawn(receiver.run()).fuse();
     let transmitter = vban::transmitter::Transmitter {
         stream_name: config.stream_name.clone(),
         dest_address: config.dest_address,
         audio_in: pa_in_recv,
         socket,
+        sealer: key
+            .as_ref()
+            .map(|key| Sealer::new(key, Arc::clone(sessions))),
+        opus,
+        fec: config
+            .fec
+            .as_ref()
+            .map(|fec| ParityEncoder::new(fec.group_size)),
+        format: tx_format,
+        keepalive_interval: Duration::from_millis(
+            config
+                .gate
+                .as_ref()
+                .map_or(GateConfig::default().keepalive_ms, |gate| gate.keepalive_ms)
+                .max(1),
+        ),
+        stats,
     };
-    let mut transmitter_thread = tokio::task::spawn(transmitter.run()).fuse();
+    let mut transmitter_thread = AbortOnDrop::spawn(transmitter.run()).fuse();
 
     loop {
         select! {
             pa_result = pa_thread => pa_result.expect("task panicked")?,
             receiver_result = receiver_thread => receiver_result.expect("task panicked")?,
             transmitter_result = transmitter_thread => transmitter_result.expect("task panicked")?,
+            _ = restart => return Err(AudioBicycleError::RestartRequested),
             complete => break,
         }
     }
@@ -0,0 +1,308 @@
+//! Serves [Stats] over HTTP, in the Prometheus text format.
+
+use std::fmt::Write;
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::stats::{Counter, Gauge, Histogram, Stats, StreamStats, HISTOGRAM_BOUNDS};
+
+/// Identifies the stream we send, and where to. Received streams are labeled with their own names and peers.
+pub struct Labels {
+    pub stream_name: String,
+    pub peer: String,
+}
+
+/// Answers requests for `/metrics` until the task is aborted.
+pub async fn serve(listener: TcpListener, stats: Arc<Stats>, labels: Labels) {
+    let labels = Arc::new(format!(
+        "stream=\"{}\",peer=\"{}\"",
+        escape(&labels.stream_name),
+        escape(&labels.peer)
+    ));
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                log::warn!("Failed to accept metrics connection: {}", e);
+                continue;
+            }
+        };
+        let stats = Arc::clone(&stats);
+        let labels = Arc::clone(&labels);
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &stats, &labels).await {
+                log::debug!("Failed to answer metrics request from {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn respond(stream: TcpStream, stats: &Stats, labels: &str) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    // Skip the headers, we don't need any of them.
+    let mut header = String::new();
+    while stream.read_line(&mut header).await? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render(stats, labels);
+            format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.get_mut().write_all(response.as_bytes()).await?;
+    stream.get_mut().shutdown().await
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// A series' name with its labels, which may be none.
+fn series(name: &str, labels: &str) -> String {
+    if labels.is_empty() {
+        format!("audio_bicycle_{name}")
+    } else {
+        format!("audio_bicycle_{name}{{{labels}}}")
+    }
+}
+
+/// Labels a received stream with its name and who's sending it now.
+fn stream_labels(stream: &StreamStats) -> String {
+    let peer = stream.peer.lock().unwrap();
+    format!(
+        "stream=\"{}\",peer=\"{}\"",
+        escape(&stream.name),
+        peer.as_ref()
+            .map(|peer| peer.address.to_string())
+            .unwrap_or_default()
+    )
+}
+
+/// What we send is labeled with its stream and destination, what we receive with each stream and its current peer. The
+/// rest covers the whole link, and isn't labeled.
+fn render(stats: &Stats, transmit_labels: &str) -> String {
+    let mut out = String::new();
+    let mut counter = |name: &str, help: &str, labels: &str, counter: &Counter| {
+        writeln!(out, "# HELP audio_bicycle_{name} {help}").unwrap();
+        writeln!(out, "# TYPE audio_bicycle_{name} counter").unwrap();
+        writeln!(out, "{} {}", series(name, labels), counter.get()).unwrap();
+    };
+    counter(
+        "packets_sent_total",
+        "Packets sent to the peer.",
+        transmit_labels,
+        &stats.packets_sent,
+    );
+    counter(
+        "bytes_sent_total",
+        "Bytes sent to the peer.",
+        transmit_labels,
+        &stats.bytes_sent,
+    );
+    counter(
+        "overruns_total",
+        "Times capture overflowed.",
+        transmit_labels,
+        &stats.overruns,
+    );
+    counter(
+        "packets_received_total",
+        "Packets received.",
+        "",
+        &stats.packets_received,
+    );
+    counter(
+        "bytes_received_total",
+        "Bytes received.",
+        "",
+        &stats.bytes_received,
+    );
+    counter(
+        "decode_failures_total",
+        "Packets that couldn't be decoded.",
+        "",
+        &stats.decode_failures,
+    );
+    counter(
+        "rejected_packets_total",
+        "Packets refused by the source policy.",
+        "",
+        &stats.rejected,
+    );
+    counter(
+        "auth_failures_total",
+        "Packets that failed decryption or replay checks.",
+        "",
+        &stats.auth_failures,
+    );
+    counter(
+        "frames_lost_total",
+        "Frames lost for good.",
+        "",
+        &stats.frames_lost,
+    );
+    counter(
+        "frames_recovered_total",
+        "Frames rebuilt from parity.",
+        "",
+        &stats.frames_recovered,
+    );
+    counter(
+        "frames_late_total",
+        "Frames that arrived too late.",
+        "",
+        &stats.frames_late,
+    );
+    counter(
+        "frames_duplicate_total",
+        "Frames that arrived more than once.",
+        "",
+        &stats.frames_duplicate,
+    );
+    counter(
+        "underruns_total",
+        "Times playback ran dry.",
+        "",
+        &stats.underruns,
+    );
+    counter(
+        "restarts_total",
+        "Times the link was restarted.",
+        "",
+        &stats.restarts,
+    );
+
+    let mut gauge = |name: &str, help: &str, labels: &str, gauge: &Gauge| {
+        writeln!(out, "# HELP audio_bicycle_{name} {help}").unwrap();
+        writeln!(out, "# TYPE audio_bicycle_{name} gauge").unwrap();
+        writeln!(out, "{} {}", series(name, labels), gauge.get()).unwrap();
+    };
+    gauge(
+        "capture_latency_seconds",
+        "How long captured audio waits to be read.",
+        transmit_labels,
+        &stats.capture_latency,
+    );
+    gauge(
+        "capture_peak",
+        "Peak level of captured audio.",
+        transmit_labels,
+        &stats.capture_peak,
+    );
+    gauge(
+        "capture_rms",
+        "RMS level of captured audio.",
+        transmit_labels,
+        &stats.capture_rms,
+    );
+    gauge(
+        "capture_agc_gain_db",
+        "Gain the AGC is applying to captured audio.",
+        transmit_labels,
+        &stats.capture_agc_gain,
+    );
+    gauge(
+        "capture_echo_reduction_db",
+        "How much quieter echo cancellation makes captured audio.",
+        transmit_labels,
+        &stats.capture_echo_reduction,
+    );
+    gauge(
+        "playback_queue_packets",
+        "Packets waiting to be played.",
+        "",
+        &stats.playback_queue,
+    );
+    gauge(
+        "playback_latency_seconds",
+        "How long until written audio is heard on the playback device.",
+        "",
+        &stats.playback.latency,
+    );
+    gauge(
+        "playback_peak",
+        "Peak level of audio played on the playback device.",
+        "",
+        &stats.playback.peak,
+    );
+    gauge(
+        "playback_rms",
+        "RMS level of audio played on the playback device.",
+        "",
+        &stats.playback.rms,
+    );
+
+    let streams: Vec<_> = stats
+        .streams()
+        .into_iter()
+        .map(|stream| (stream_labels(&stream), stream))
+        .collect();
+    let mut stream_gauge = |name: &str, help: &str, value: &dyn Fn(&StreamStats) -> f64| {
+        writeln!(out, "# HELP audio_bicycle_{name} {help}").unwrap();
+        writeln!(out, "# TYPE audio_bicycle_{name} gauge").unwrap();
+        for (labels, stream) in &streams {
+            writeln!(out, "{} {}", series(name, labels), value(stream)).unwrap();
+        }
+    };
+    stream_gauge(
+        "jitter_seconds",
+        "How much the time between packets varies.",
+        &|stream| stream.jitter.get(),
+    );
+    stream_gauge(
+        "stream_playback_latency_seconds",
+        "How long until written audio is heard, for a stream on a sink of its own.",
+        &|stream| stream.playback.latency.get(),
+    );
+    stream_gauge(
+        "stream_playback_peak",
+        "Peak level of played audio, for a stream on a sink of its own.",
+        &|stream| stream.playback.peak.get(),
+    );
+    stream_gauge(
+        "stream_playback_rms",
+        "RMS level of played audio, for a stream on a sink of its own.",
+        &|stream| stream.playback.rms.get(),
+    );
+
+    let mut histogram = |name: &str, help: &str, histogram: &Histogram| {
+        writeln!(out, "# HELP audio_bicycle_{name} {help}").unwrap();
+        writeln!(out, "# TYPE audio_bicycle_{name} histogram").unwrap();
+        for (bound, count) in HISTOGRAM_BOUNDS.iter().zip(histogram.cumulative_buckets()) {
+            writeln!(out, "audio_bicycle_{name}_bucket{{le=\"{bound}\"}} {count}").unwrap();
+        }
+        let count = histogram.count();
+        writeln!(out, "audio_bicycle_{name}_bucket{{le=\"+Inf\"}} {count}").unwrap();
+        writeln!(out, "audio_bicycle_{name}_sum {}", histogram.sum()).unwrap();
+        writeln!(out, "audio_bicycle_{name}_count {count}").unwrap();
+    };
+    histogram(
+        "packet_interval_seconds",
+        "Time between packets arriving.",
+        &stats.packet_interval,
+    );
+    histogram(
+        "playback_buffer_seconds",
+        "Playback latency, sampled on each write.",
+        &stats.playback_latency_histogram,
+    );
+    out
+}
@@ -0,0 +1,399 @@
+//! A full-screen dashboard for the running service, fed by the control socket.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::Duration;
+
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind};
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use crossterm::ExecutableCommand;
+use futures::StreamExt;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::symbols::Marker;
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Axis, Block, Chart, Dataset, Gauge, GraphType, List, Paragraph};
+use ratatui::{Frame, Terminal};
+
+use crate::control::client::send;
+use crate::control::protocol::{Direction, Request, Status};
+
+/// How often to ask the service for news.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+/// How many refreshes the graph covers.
+const HISTORY_LEN: usize = 240;
+/// The quietest level the meters show, in dBFS.
+const METER_FLOOR_DB: f64 = -60.0;
+
+struct Monitor {
+    status: Option<Status>,
+    log: Vec<String>,
+    /// The worst stream's jitter and the playback latency from each refresh, in milliseconds.
+    history: VecDeque<(f64, f64)>,
+    /// Which direction the keys adjust.
+    selected: Direction,
+    /// Why the last request failed, if it did.
+    error: Option<String>,
+}
+
+/// Runs the dashboard until the user quits.
+pub async fn run(socket_path: &Path) -> std::io::Result<()> {
+    enable_raw_mode()?;
+    std::io::stdout().execute(EnterAlternateScreen)?;
+    // Put the terminal back even if we panic, or the panic message is unreadable.
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = restore_terminal();
+        default_hook(info);
+    }));
+
+    let result = run_ui(socket_path).await;
+    restore_terminal()?;
+    result
+}
+
+fn restore_terminal() -> std::io::Result<()> {
+    disable_raw_mode()?;
+    std::io::stdout().execute(LeaveAlternateScreen)?;
+    Ok(())
+}
+
+async fn run_ui(socket_path: &Path) -> std::io::Result<()> {
+    let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
+    let mut monitor = Monitor {
+        status: None,
+        log: Vec::new(),
+        history: VecDeque::with_capacity(HISTORY_LEN),
+        selected: Direction::Tx,
+        error: None,
+    };
+    let mut events = EventStream::new();
+    let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+    refresh.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        terminal.draw(|frame| monitor.draw(frame))?;
+        tokio::select! {
+            _ = refresh.tick() => monitor.refresh(socket_path).await,
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind != KeyEventKind::Release => {
+                    if !monitor.handle_key(key, socket_path).await {
+                        return Ok(());
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+impl Monitor {
+    async fn refresh(&mut self, socket_path: &Path) {
+        let result = async {
+            let status = send(socket_path, &Request::Status).await?.status;
+            let log = send(socket_path, &Request::Log).await?.log;
+            Ok::<_, crate::control::client::ClientError>((status, log))
+        }
+        .await;
+        match result {
+            Ok((status, log)) => {
+                if let Some(status) = &status {
+                    if self.history.len() == HISTORY_LEN {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back((
+                        status
+                            .stats
+                            .streams
+                            .iter()
+                            .map(|stream| stream.jitter)
+                            .fold(0.0, f64::max)
+                            * 1000.0,
+                        status.stats.playback_latency * 1000.0,
+                    ));
+                }
+                self.status = status;
+                self.log = log.unwrap_or_default();
+                self.error = None;
+            }
+            Err(e) => self.error = Some(e.to_string()),
+        }
+    }
+
+    /// Returns false when it's time to quit.
+    async fn handle_key(&mut self, key: KeyEvent, socket_path: &Path) -> bool {
+        let direction = self.selected;
+        let controls = self.status.as_ref().map(|status| match direction {
+            Direction::Tx => &status.tx,
+            Direction::Rx => &status.rx,
+        });
+        let request = match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Tab | KeyCode::Left | KeyCode::Right => {
+                self.selected = match direction {
+                    Direction::Tx => Direction::Rx,
+                    Direction::Rx => Direction::Tx,
+                };
+                return true;
+            }
+            KeyCode::Char('m') => Request::Mute {
+                direction,
+                muted: !controls.is_some_and(|c| c.muted),
+            },
+            KeyCode::Char('+') | KeyCode::Char('=') | KeyCode::Up => Request::Gain {
+                direction,
+                db: controls.map_or(0.0, |c| c.gain_db) + 1.0,
+            },
+            KeyCode::Char('-') | KeyCode::Down => Request::Gain {
+                direction,
+                db: controls.map_or(0.0, |c| c.gain_db) - 1.0,
+            },
+            KeyCode::Char('0') => Request::Gain { direction, db: 0.0 },
+            KeyCode::Char('n') => Request::Denoise {
+                enabled: !self.status.as_ref().is_some_and(|s| s.denoise),
+            },
+            KeyCode::Char('l') => Request::Limiter {
+                direction,
+                enabled: !controls.is_some_and(|c| c.limiter),
+            },
+            _ => return true,
+        };
+        match send(socket_path, &request).await {
+            Ok(_) => self.refresh(socket_path).await,
+            Err(e) => self.error = Some(e.to_string()),
+        }
+        true
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        // A line for each received stream, and one for what we send.
+        let header_lines = self
+            .status
+            .as_ref()
+            .map_or(1, |status| status.stats.streams.len().max(1) + 1);
+        let [header, meters, graph, bottom, footer] = Layout::vertical([
+            Constraint::Length(header_lines as u16 + 2),
+            Constraint::Length(6),
+            Constraint::Min(8),
+            Constraint::Min(8),
+            Constraint::Length(1),
+        ])
+        .areas(frame.size());
+        let [counters, log] =
+            Layout::horizontal([Constraint::Length(32), Constraint::Min(20)]).areas(bottom);
+
+        self.draw_header(frame, header);
+        self.draw_meters(frame, meters);
+        self.draw_graph(frame, graph);
+        self.draw_counters(frame, counters);
+
+        let lines = self
+            .log
+            .iter()
+            .skip(
+                self.log
+                    .len()
+                    .saturating_sub(log.height.saturating_sub(2) as usize),
+            )
+            .map(|line| line.as_str());
+        frame.render_widget(List::new(lines).block(Block::bordered().title("Log")), log);
+
+        let help = "q quit · tab switch direction · m mute · +/- gain · 0 reset gain · l limiter · n denoise";
+        let footer_line = match &self.error {
+            Some(error) => Line::from(error.as_str()).red(),
+            None => Line::from(help).dark_gray(),
+        };
+        frame.render_widget(Paragraph::new(footer_line), footer);
+    }
+
+    fn draw_header(&self, frame: &mut Frame, area: Rect) {
+        let lines = match &self.status {
+            Some(status) => {
+                let mut lines: Vec<_> = status
+                    .stats
+                    .streams
+                    .iter()
+                    .map(|stream| match &stream.peer {
+                        Some(peer) => format!(
+                            "{} from {} · {} · last packet {:.1} s ago · jitter {:.2} ms",
+                            stream.name,
+                            peer.address,
+                            peer.format,
+                            peer.seconds_since_last_packet,
+                            stream.jitter * 1000.0
+                        ),
+                        None => format!("{}: no audio received yet", stream.name),
+                    })
+                    .map(Line::from)
+                    .collect();
+                if lines.is_empty() {
+                    lines.push(Line::from("No audio received yet"));
+                }
+                lines.push(Line::from(format!(
+                    "Sending {}",
+                    status.tx.format.as_deref().unwrap_or("unknown format")
+                )));
+                lines
+            }
+            None => vec![Line::from("Connecting...")],
+        };
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("audio-bicycle")),
+            area,
+        );
+    }
+
+    fn draw_meters(&self, frame: &mut Frame, area: Rect) {
+        let [tx, rx] =
+            Layout::horizontal([Constraint::Ratio(1, 2), Constraint::Ratio(1, 2)]).areas(area);
+        for (direction, area) in [(Direction::Tx, tx), (Direction::Rx, rx)] {
+            let name = match direction {
+                Direction::Tx => "Send",
+                Direction::Rx => "Receive",
+            };
+            let Some(status) = &self.status else {
+                frame.render_widget(Block::bordered().title(name), area);
+                continue;
+            };
+            let (controls, peak, rms) = match direction {
+                Direction::Tx => (
+                    &status.tx,
+                    status.stats.capture_peak,
+                    status.stats.capture_rms,
+                ),
+                Direction::Rx => (
+                    &status.rx,
+                    status.stats.playback_peak,
+                    status.stats.playback_rms,
+                ),
+            };
+            let mut title = vec![Span::raw(format!(" {} ", name))];
+            if controls.muted {
+                title.push(Span::raw("MUTED ").red().bold());
+            }
+            title.push(Span::raw(format!("{:+.1} dB ", controls.gain_db)));
+            if controls.limiter {
+                title.push(Span::raw("limiter "));
+            }
+            if direction == Direction::Tx && status.denoise {
+                title.push(Span::raw("denoise "));
+            }
+            let mut block = Block::bordered().title(Line::from(title));
+            if self.selected == direction {
+                block = block.border_style(Style::new().fg(Color::Cyan));
+            }
+            let inner = block.inner(area);
+            frame.render_widget(block, area);
+            let [peak_area, rms_area] =
+                Layout::vertical([Constraint::Length(2), Constraint::Length(2)]).areas(inner);
+            frame.render_widget(meter("Peak", peak), peak_area);
+            frame.render_widget(meter("RMS", rms), rms_area);
+        }
+    }
+
+    fn draw_graph(&self, frame: &mut Frame, area: Rect) {
+        let jitter: Vec<(f64, f64)> = self
+            .history
+            .iter()
+            .enumerate()
+            .map(|(i, &(jitter, _))| (i as f64, jitter))
+            .collect();
+        let latency: Vec<(f64, f64)> = self
+            .history
+            .iter()
+            .enumerate()
+            .map(|(i, &(_, latency))| (i as f64, latency))
+            .collect();
+        let max = self
+            .history
+            .iter()
+            .map(|&(jitter, latency)| jitter.max(latency))
+            .fold(1.0, f64::max);
+        let seconds = HISTORY_LEN as f64 * REFRESH_INTERVAL.as_secs_f64();
+        let chart = Chart::new(vec![
+            Dataset::default()
+                .name("jitter")
+                .marker(Marker::Braille)
+                .graph_type(GraphType::Line)
+                .yellow()
+                .data(&jitter),
+            Dataset::default()
+                .name("playback latency")
+                .marker(Marker::Braille)
+                .graph_type(GraphType::Line)
+                .cyan()
+                .data(&latency),
+        ])
+        .block(Block::bordered().title("Jitter and latency (ms)"))
+        .x_axis(
+            Axis::default()
+                .bounds([0.0, HISTORY_LEN as f64])
+                .labels(vec![
+                    Span::raw(format!("-{:.0} s", seconds)),
+                    Span::raw("now"),
+                ]),
+        )
+        .y_axis(
+            Axis::default()
+                .bounds([0.0, max])
+                .labels(vec![Span::raw("0"), Span::raw(format!("{:.1}", max))]),
+        );
+        frame.render_widget(chart, area);
+    }
+
+    fn draw_counters(&self, frame: &mut Frame, area: Rect) {
+        let lines = match &self.status {
+            Some(status) => {
+                let stats = &status.stats;
+                [
+                    ("Packets sent", stats.packets_sent),
+                    ("Packets received", stats.packets_received),
+                    ("Frames lost", stats.frames_lost),
+                    ("Frames recovered", stats.frames_recovered),
+                    ("Frames late", stats.frames_late),
+                    ("Frames duplicate", stats.frames_duplicate),
+                    ("Rejected", stats.rejected),
+                    ("Auth failures", stats.auth_failures),
+                    ("Decode failures", stats.decode_failures),
+                    ("Underruns", stats.underruns),
+                    ("Overruns", stats.overruns),
+                    ("Restarts", stats.restarts),
+                ]
+                .into_iter()
+                .map(|(name, value)| Line::from(format!("{:<18}{:>10}", name, value)))
+                .collect()
+            }
+            None => Vec::new(),
+        };
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Link")),
+            area,
+        );
+    }
+}
+
+/// A meter for a level given as a fraction of full scale, drawn on a dB scale.
+fn meter(name: &str, level: f64) -> Gauge<'static> {
+    let db = 20.0 * level.max(1e-9).log10();
+    let ratio = ((db - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0);
+    let color = if db > -1.0 {
+        Color::Red
+    } else if db > -12.0 {
+        Color::Yellow
+    } else {
+        Color::Green
+    };
+    let label = if db <= METER_FLOOR_DB {
+        format!("{} -inf dBFS", name)
+    } else {
+        format!("{} {:.1} dBFS", name, db)
+    };
+    Gauge::default()
+        .gauge_style(Style::new().fg(color))
+        .ratio(ratio)
+        .label(label)
+}
@@ -0,0 +1,4 @@
+//! A control surface for OSC desks and apps, over UDP.
+
+pub(crate) mod packet;
+pub(crate) mod server;
@@ -0,0 +1,240 @@
+//! Just enough of OSC 1.0 to talk to control surfaces.
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum OscError {
+    #[error("Packet ended early")]
+    Truncated,
+    #[error("String isn't valid UTF-8")]
+    InvalidString,
+    #[error("Address must start with '/'")]
+    InvalidAddress,
+    #[error("Missing type tags")]
+    MissingTypeTags,
+    #[error("Unsupported argument type '{0}'")]
+    UnsupportedType(char),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Bool(bool),
+}
+
+impl OscArg {
+    /// Reads a number, however the sender chose to send it.
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            OscArg::Int(i) => Some(i as f32),
+            OscArg::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    /// Reads a switch. Buttons often send floats, so anything from 0.5 up is on.
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            OscArg::Bool(b) => Some(b),
+            OscArg::Int(i) => Some(i != 0),
+            OscArg::Float(f) => Some(f >= 0.5),
+            OscArg::String(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    pub fn new(address: impl Into<String>, args: Vec<OscArg>) -> Self {
+        Self {
+            address: address.into(),
+            args,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_string(&mut out, &self.address);
+        let mut tags = String::from(",");
+        for arg in &self.args {
+            tags.push(match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::String(_) => 's',
+                OscArg::Bool(true) => 'T',
+                OscArg::Bool(false) => 'F',
+            });
+        }
+        write_string(&mut out, &tags);
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(i) => out.extend_from_slice(&i.to_be_bytes()),
+                OscArg::Float(f) => out.extend_from_slice(&f.to_be_bytes()),
+                OscArg::String(s) => write_string(&mut out, s),
+                OscArg::Bool(_) => {}
+            }
+        }
+        out
+    }
+}
+
+/// Decodes a packet into its messages, flattening any bundles. Time tags are ignored, everything happens now.
+pub fn decode(data: &[u8]) -> Result<Vec<OscMessage>, OscError> {
+    let mut messages = Vec::new();
+    decode_into(data, &mut messages)?;
+    Ok(messages)
+}
+
+fn decode_into(data: &[u8], messages: &mut Vec<OscMessage>) -> Result<(), OscError> {
+    let mut reader = Reader { data };
+    if data.starts_with(b"#bundle\0") {
+        reader.take(16)?; // The "#bundle" string, and the time tag.
+        while !reader.data.is_empty() {
+            let size = reader.i32()?;
+            let size = usize::try_from(size).map_err(|_| OscError::Truncated)?;
+            decode_into(reader.take(size)?, messages)?;
+        }
+        return Ok(());
+    }
+
+    let address = reader.string()?;
+    if !address.starts_with('/') {
+        return Err(OscError::InvalidAddress);
+    }
+    // Some old senders leave out the type tags when there are no arguments.
+    if reader.data.is_empty() {
+        messages.push(OscMessage::new(address, Vec::new()));
+        return Ok(());
+    }
+    let tags = reader.string()?;
+    let tags = tags.strip_prefix(',').ok_or(OscError::MissingTypeTags)?;
+    let mut args = Vec::with_capacity(tags.len());
+    for tag in tags.chars() {
+        args.push(match tag {
+            'i' => OscArg::Int(reader.i32()?),
+            'f' => OscArg::Float(f32::from_bits(reader.i32()? as u32)),
+            's' => OscArg::String(reader.string()?.to_string()),
+            'T' => OscArg::Bool(true),
+            'F' => OscArg::Bool(false),
+            other => return Err(OscError::UnsupportedType(other)),
+        });
+    }
+    messages.push(OscMessage::new(address, args));
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], OscError> {
+        if self.data.len() < len {
+            return Err(OscError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn i32(&mut self) -> Result<i32, OscError> {
+        let bytes = self.take(4)?;
+        Ok(i32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Reads a null-terminated string, padded to a multiple of 4 bytes.
+    fn string(&mut self) -> Result<&'a str, OscError> {
+        let len = self
+            .data
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(OscError::Truncated)?;
+        let padded = (len + 4) & !3;
+        let bytes = self.take(padded.min(self.data.len()))?;
+        std::str::from_utf8(&bytes[..len]).map_err(|_| OscError::InvalidString)
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(s.as_bytes());
+    let padded = (s.len() + 4) & !3;
+    out.resize(out.len() + padded - s.len(), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_padded_strings_and_arguments() {
+        let message = OscMessage::new("/bicycle/rx/gain", vec![OscArg::Float(-6.0)]);
+        let mut expected = b"/bicycle/rx/gain\0\0\0\0,f\0\0".to_vec();
+        expected.extend_from_slice(&(-6.0f32).to_be_bytes());
+        assert_eq!(message.encode(), expected);
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let message = OscMessage::new(
+            "/bicycle/status/peer",
+            vec![
+                OscArg::String("192.168.1.12:6980".to_string()),
+                OscArg::Int(-3),
+                OscArg::Float(0.25),
+                OscArg::Bool(true),
+                OscArg::Bool(false),
+            ],
+        );
+        assert_eq!(decode(&message.encode()).unwrap(), vec![message]);
+    }
+
+    #[test]
+    fn flattens_bundles() {
+        let first = OscMessage::new("/bicycle/tx/mute", vec![OscArg::Int(1)]);
+        let second = OscMessage::new("/bicycle/status", Vec::new());
+        let mut bundle = b"#bundle\0".to_vec();
+        bundle.extend_from_slice(&1u64.to_be_bytes());
+        for message in [&first, &second] {
+            let encoded = message.encode();
+            bundle.extend_from_slice(&(encoded.len() as i32).to_be_bytes());
+            bundle.extend_from_slice(&encoded);
+        }
+        assert_eq!(decode(&bundle).unwrap(), vec![first, second]);
+    }
+
+    #[test]
+    fn accepts_messages_without_type_tags() {
+        let messages = decode(b"/bicycle/status\0").unwrap();
+        assert_eq!(
+            messages,
+            vec![OscMessage::new("/bicycle/status", Vec::new())]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        assert!(matches!(
+            decode(b"bicycle\0"),
+            Err(OscError::InvalidAddress)
+        ));
+        assert!(matches!(
+            decode(b"/a\0\0,i\0\0\0\0"),
+            Err(OscError::Truncated)
+        ));
+        assert!(matches!(
+            decode(b"/a\0\0i\0\0\0"),
+            Err(OscError::MissingTypeTags)
+        ));
+        assert!(matches!(
+            decode(b"/a\0\0,d\0\0"),
+            Err(OscError::UnsupportedType('d'))
+        ));
+    }
+}
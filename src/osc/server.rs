@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+
+use crate::config::source_policy::SourceNet;
+use crate::control::protocol::Direction;
+use crate::control::{Controls, GAIN_DB_RANGE};
+use crate::osc::packet::{decode, OscArg, OscMessage};
+use crate::stats::{Gauge, Stats};
+
+/// How long a subscription lasts without being renewed.
+const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(60);
+/// How many surfaces can subscribe at once.
+const MAX_SUBSCRIBERS: usize = 16;
+
+/// Answers OSC messages, and pushes level meters to subscribers, until the task is aborted.
+pub struct OscServer {
+    pub socket: UdpSocket,
+    pub controls: Arc<Controls>,
+    pub stats: Arc<Stats>,
+    pub meter_interval: Duration,
+    /// Who besides this machine may subscribe. Subscribing only takes one packet, so a forged source address would
+    /// otherwise send a stream of meters to anyone.
+    pub subscribe_from: Vec<SourceNet>,
+}
+
+impl OscServer {
+    pub async fn run(self) {
+        let mut subscribers = HashMap::<SocketAddr, Instant>::new();
+        let mut meter = tokio::time::interval(self.meter_interval);
+        meter.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut buf = [0u8; 1536];
+        loop {
+            tokio::select! {
+                received = self.socket.recv_from(&mut buf) => {
+                    let (len, addr) = match received {
+                        Ok(v) => v,
+                        Err(e) => {
+                            log::warn!("Failed to receive OSC message: {}", e);
+                            continue;
+                        }
+                    };
+                    match decode(&buf[..len]) {
+                        Ok(messages) => {
+                            for message in messages {
+                                self.handle(message, addr, &mut subscribers).await;
+                            }
+                        }
+                        Err(e) => log::debug!("Ignoring invalid OSC packet from {}: {}", addr, e),
+                    }
+                }
+                _ = meter.tick() => {
+                    let now = Instant::now();
+                    subscribers.retain(|_, expiry| *expiry > now);
+                    if subscribers.is_empty() {
+                        continue;
+                    }
+                    let meters = [
+                        level("/bicycle/tx/level", &self.stats.capture_peak, &self.stats.capture_rms),
+                        level("/bicycle/rx/level", &self.stats.playback.peak, &self.stats.playback.rms),
+                    ];
+                    self.broadcast(&meters, &subscribers).await;
+                }
+            }
+        }
+    }
+
+    async fn handle(
+        &self,
+        message: OscMessage,
+        addr: SocketAddr,
+        subscribers: &mut HashMap<SocketAddr, Instant>,
+    ) {
+        let Some(path) = message.address.strip_prefix("/bicycle/") else {
+            log::debug!("Ignoring OSC message for {} from {}", message.address, addr);
+            return;
+        };
+        let first_arg = message.args.first();
+        match path.split('/').collect::<Vec<_>>()[..] {
+            ["status"] => self.send(&self.status(), addr).await,
+            ["subscribe"] => {
+                let ip = addr.ip();
+                if !ip.to_canonical().is_loopback()
+                    && !self.subscribe_from.iter().any(|net| net.contains(ip))
+                {
+                    log::debug!("Ignoring subscription from {}, which isn't allowed", addr);
+                    return;
+                }
+                if !subscribers.contains_key(&addr) && subscribers.len() >= MAX_SUBSCRIBERS {
+                    log::warn!(
+                        "Ignoring subscription from {}, too many surfaces are subscribed",
+                        addr
+                    );
+                    return;
+                }
+                subscribers.insert(addr, Instant::now() + SUBSCRIPTION_TIMEOUT);
+                // Bring the surface up to date, so its faders and buttons match.
+                self.send(&self.status(), addr).await;
+            }
+            ["unsubscribe"] => {
+                subscribers.remove(&addr);
+            }
+            ["restart"] => {
+                log::info!("Restart requested over OSC by {}", addr);
+                self.controls.request_restart();
+            }
+            [direction @ ("tx" | "rx"), control @ ("mute" | "gain" | "limiter")] => {
+                let direction = if direction == "tx" {
+                    Direction::Tx
+                } else {
+                    Direction::Rx
+                };
+                let controls = self.controls.direction(direction);
+                match (control, first_arg) {
+                    // With no arguments, this is a question.
+                    (_, None) => {}
+                    ("mute", Some(arg)) => match arg.as_bool() {
+                        Some(muted) => controls.set_muted(muted),
+                        None => {
+                            log::debug!("Invalid mute value from {}", addr);
+                            return;
+                        }
+                    },
+                    ("limiter", Some(arg)) => match arg.as_bool() {
+                        Some(enabled) => controls.set_limiter(enabled),
+                        None => {
+                            log::debug!("Invalid limiter value from {}", addr);
+                            return;
+                        }
+                    },
+                    (_, Some(arg)) => match arg.as_f32().filter(|db| GAIN_DB_RANGE.contains(db)) {
+                        Some(db) => controls.set_gain_db(db),
+                        None => {
+                            log::debug!("Invalid gain value from {}", addr);
+                            return;
+                        }
+                    },
+                }
+                let reply = [self.direction_value(direction, control)];
+                self.reply(&reply, first_arg.is_some(), addr, subscribers)
+                    .await;
+            }
+            ["tx", "denoise"] => {
+                if let Some(arg) = first_arg {
+                    match arg.as_bool() {
+                        Some(enabled) => self.controls.set_denoise(enabled),
+                        None => {
+                            log::debug!("Invalid denoise value from {}", addr);
+                            return;
+                        }
+                    }
+                }
+                let reply = [self.denoise_value()];
+                self.reply(&reply, first_arg.is_some(), addr, subscribers)
+                    .await;
+            }
+            _ => log::debug!("Ignoring OSC message for {} from {}", message.address, addr),
+        }
+    }
+
+    /// Answers a question about a control, or tells everyone about a change to it.
+    async fn reply(
+        &self,
+        reply: &[OscMessage],
+        changed: bool,
+        addr: SocketAddr,
+        subscribers: &HashMap<SocketAddr, Instant>,
+    ) {
+        if changed {
+            // Keep every surface's fader in the same place.
+            self.broadcast(reply, subscribers).await;
+        }
+        if !subscribers.contains_key(&addr) {
+            self.send(reply, addr).await;
+        }
+    }
+
+    fn denoise_value(&self) -> OscMessage {
+        OscMessage::new(
+            "/bicycle/tx/denoise",
+            vec![OscArg::Int(i32::from(self.controls.denoise()))],
+        )
+    }
+
+    fn direction_value(&self, direction: Direction, control: &str) -> OscMessage {
+        let name = match direction {
+            Direction::Tx => "tx",
+            Direction::Rx => "rx",
+        };
+        let controls = self.controls.direction(direction);
+        let value = match control {
+            "mute" => OscArg::Int(i32::from(controls.muted())),
+            "limiter" => OscArg::Int(i32::from(controls.limiter())),
+            _ => OscArg::Float(controls.gain_db()),
+        };
+        OscMessage::new(format!("/bicycle/{}/{}", name, control), vec![value])
+    }
+
+    fn status(&self) -> Vec<OscMessage> {
+        let mut messages = Vec::new();
+        let stats = self.stats.snapshot();
+        for stream in &stats.streams {
+            let Some(peer) = &stream.peer else {
+                continue;
+            };
+            messages.push(OscMessage::new(
+                "/bicycle/status/peer",
+                vec![
+                    OscArg::String(peer.address.to_string()),
+                    OscArg::String(peer.format.clone()),
+                    OscArg::Float(peer.seconds_since_last_packet as f32),
+                    OscArg::String(stream.name.clone()),
+                    OscArg::Float(stream.jitter as f32),
+                ],
+            ));
+        }
+        for direction in [Direction::Tx, Direction::Rx] {
+            messages.push(self.direction_value(direction, "mute"));
+            messages.push(self.direction_value(direction, "gain"));
+            messages.push(self.direction_value(direction, "limiter"));
+        }
+        messages.push(self.denoise_value());
+        let count = |n: u64| OscArg::Int(i32::try_from(n).unwrap_or(i32::MAX));
+        messages.push(OscMessage::new(
+            "/bicycle/status/latency",
+            vec![
+                OscArg::Float(stats.playback_latency as f32),
+                OscArg::Float(stats.capture_latency as f32),
+                // The worst of the streams, as each one's is sent with its peer.
+                OscArg::Float(
+                    stats
+                        .streams
+                        .iter()
+                        .map(|stream| stream.jitter)
+                        .fold(0.0, f64::max) as f32,
+                ),
+            ],
+        ));
+        messages.push(OscMessage::new(
+            "/bicycle/status/frames",
+            vec![
+                count(stats.frames_lost),
+                count(stats.frames_recovered),
+                count(stats.underruns),
+                count(stats.overruns),
+            ],
+        ));
+        messages
+    }
+
+    async fn send(&self, messages: &[OscMessage], addr: SocketAddr) {
+        for message in messages {
+            if let Err(e) = self.socket.send_to(&message.encode(), addr).await {
+                log::debug!("Failed to send OSC message to {}: {}", addr, e);
+            }
+        }
+    }
+
+    async fn broadcast(&self, messages: &[OscMessage], subscribers: &HashMap<SocketAddr, Instant>) {
+        for &addr in subscribers.keys() {
+            self.send(messages, addr).await;
+        }
+    }
+}
+
+fn level(address: &str, peak: &Gauge, rms: &Gauge) -> OscMessage {
+    OscMessage::new(
+        address,
+        vec![
+            OscArg::Float(peak.get() as f32),
+            OscArg::Float(rms.get() as f32),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    /// A server, and a local client to talk to it with.
+    async fn setup() -> (OscServer, UdpSocket) {
+        let server = OscServer {
+            socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            controls: Arc::new(Controls::default()),
+            stats: Arc::new(Stats::default()),
+            meter_interval: Duration::from_millis(100),
+            subscribe_from: Vec::new(),
+        };
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        (server, client)
+    }
+
+    async fn receive(client: &UdpSocket) -> OscMessage {
+        let mut buf = [0u8; 1536];
+        let len = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buf))
+            .await
+            .expect("should get a reply")
+            .unwrap();
+        decode(&buf[..len]).unwrap().remove(0)
+    }
+
+    #[tokio::test]
+    async fn sets_gain_and_replies() {
+        let (server, client) = setup().await;
+        let addr = client.local_addr().unwrap();
+        let message = OscMessage::new("/bicycle/rx/gain", vec![OscArg::Float(-6.0)]);
+        server
+            .handle(message.clone(), addr, &mut HashMap::new())
+            .await;
+        assert_eq!(server.controls.direction(Direction::Rx).gain_db(), -6.0);
+        assert_eq!(receive(&client).await, message);
+    }
+
+    #[tokio::test]
+    async fn takes_floats_as_switches() {
+        let (server, client) = setup().await;
+        let addr = client.local_addr().unwrap();
+        let message = OscMessage::new("/bicycle/tx/mute", vec![OscArg::Float(1.0)]);
+        server.handle(message, addr, &mut HashMap::new()).await;
+        assert!(server.controls.direction(Direction::Tx).muted());
+        let reply = OscMessage::new("/bicycle/tx/mute", vec![OscArg::Int(1)]);
+        assert_eq!(receive(&client).await, reply);
+    }
+
+    #[tokio::test]
+    async fn ignores_invalid_values() {
+        let (server, client) = setup().await;
+        let addr = client.local_addr().unwrap();
+        for value in [OscArg::Float(f32::NAN), OscArg::String("loud".to_string())] {
+            let message = OscMessage::new("/bicycle/tx/gain", vec![value]);
+            server.handle(message, addr, &mut HashMap::new()).await;
+        }
+        assert_eq!(server.controls.direction(Direction::Tx).gain_db(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn subscribing_sends_status() {
+        let (server, client) = setup().await;
+        let addr = client.local_addr().unwrap();
+        let mut subscribers = HashMap::new();
+        let message = OscMessage::new("/bicycle/subscribe", Vec::new());
+        server.handle(message, addr, &mut subscribers).await;
+        assert!(subscribers.contains_key(&addr));
+        assert_eq!(receive(&client).await.address, "/bicycle/tx/mute");
+    }
+
+    #[tokio::test]
+    async fn only_allows_local_subscribers() {
+        let (server, _client) = setup().await;
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 9000);
+        let mut subscribers = HashMap::new();
+        let message = OscMessage::new("/bicycle/subscribe", Vec::new());
+        server.handle(message, addr, &mut subscribers).await;
+        assert!(subscribers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn limits_subscribers() {
+        let (server, _client) = setup().await;
+        let mut subscribers = HashMap::new();
+        for port in 1..=MAX_SUBSCRIBERS as u16 + 1 {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+            let message = OscMessage::new("/bicycle/subscribe", Vec::new());
+            server.handle(message, addr, &mut subscribers).await;
+        }
+        assert_eq!(subscribers.len(), MAX_SUBSCRIBERS);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::vban::packet::DataType;
+
+/// The largest value of a signed 24-bit sample, as a float.
+const S24_MAX: f32 = 8_388_607.0;
+
+/// Converts interleaved signed 24-bit little-endian samples to floats in `-1.0..=1.0`.
+pub fn s24le_to_f32(bytes: &[u8], out: &mut Vec<f32>) {
+    out.extend(bytes.chunks_exact(3).map(|sample| {
+        let value = i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) >> 8;
+        value as f32 / S24_MAX
+    }));
+}
+
+/// Converts floats to interleaved signed 24-bit little-endian samples, clipping anything out of range.
+pub fn f32_to_s24le(samples: &[f32], out: &mut Vec<u8>) {
+    out.reserve(samples.len() * 3);
+    for &sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * S24_MAX).round() as i32;
+        out.extend_from_slice(&value.to_le_bytes()[..3]);
+    }
+}
+
+/// The peak and RMS levels of signed 24-bit samples, as fractions of full scale.
+pub fn s24le_levels(bytes: &[u8]) -> (f32, f32) {
+    let mut samples = Vec::with_capacity(bytes.len() / 3);
+    s24le_to_f32(bytes, &mut samples);
+    if samples.is_empty() {
+        return (0.0, 0.0);
+    }
+    let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+    let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+    (peak, rms)
+}
+
+/// Converts interleaved little-endian samples of any VBAN type to signed 24-bit, or `None` if the type isn't supported.
+/// A trailing partial sample is dropped.
+pub fn to_s24le(bytes: &[u8], data_type: DataType) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(bytes.len() / data_type.sample_size()? * 3);
+    match data_type {
+        DataType::I24 => out.extend_from_slice(&bytes[..bytes.len() / 3 * 3]),
+        DataType::U8 => {
+            for &sample in bytes {
+                out.extend_from_slice(&((i32::from(sample) - 128) << 16).to_le_bytes()[..3]);
+            }
+        }
+        DataType::I16 => {
+            for sample in bytes.chunks_exact(2) {
+                let value = i32::from(i16::from_le_bytes([sample[0], sample[1]])) << 8;
+                out.extend_from_slice(&value.to_le_bytes()[..3]);
+            }
+        }
+        DataType::I32 => {
+            for sample in bytes.chunks_exact(4) {
+                out.extend_from_slice(&sample[1..]);
+            }
+        }
+        DataType::F32 => {
+            let samples: Vec<f32> = bytes
+                .chunks_exact(4)
+                .map(|sample| f32::from_le_bytes(sample.try_into().unwrap()))
+                .collect();
+            f32_to_s24le(&samples, &mut out);
+        }
+        DataType::F64 => {
+            let samples: Vec<f32> = bytes
+                .chunks_exact(8)
+                .map(|sample| f64::from_le_bytes(sample.try_into().unwrap()) as f32)
+                .collect();
+            f32_to_s24le(&samples, &mut out);
+        }
+        DataType::I12 | DataType::I10 => return None,
+    }
+    Some(out)
+}
+
+/// Converts interleaved signed 24-bit samples to another VBAN type, or `None` if the type isn't supported.
+pub fn from_s24le(bytes: &[u8], data_type: DataType) -> Option<Vec<u8>> {
+    let samples = bytes
+        .chunks_exact(3)
+        .map(|sample| i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) >> 8);
+    let mut out = Vec::with_capacity(bytes.len() / 3 * data_type.sample_size()?);
+    match data_type {
+        DataType::I24 => out.extend_from_slice(&bytes[..bytes.len() / 3 * 3]),
+        DataType::U8 => out.extend(samples.map(|sample| ((sample >> 16) + 128) as u8)),
+        DataType::I16 => {
+            for sample in samples {
+                out.extend_from_slice(&((sample >> 8) as i16).to_le_bytes());
+            }
+        }
+        DataType::I32 => {
+            for sample in samples {
+                out.extend_from_slice(&(sample << 8).to_le_bytes());
+            }
+        }
+        DataType::F32 => {
+            for sample in samples {
+                out.extend_from_slice(&(sample as f32 / S24_MAX).to_le_bytes());
+            }
+        }
+        DataType::F64 => {
+            for sample in samples {
+                out.extend_from_slice(&(f64::from(sample) / f64::from(S24_MAX)).to_le_bytes());
+            }
+        }
+        DataType::I12 | DataType::I10 => return None,
+    }
+    Some(out)
+}
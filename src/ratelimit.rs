@@ -0,0 +1,33 @@
+use std::time::{Duration, Instant};
+
+/// Limits how often a repeated message is logged, counting the ones that get suppressed.
+pub struct RateLimit {
+    interval: Duration,
+    last_allowed: Option<Instant>,
+    suppressed: u64,
+}
+
+impl RateLimit {
+    pub const fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_allowed: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Should the message be logged now?
+    /// If so, returns how many were suppressed since the last time it was.
+    pub fn check(&mut self) -> Option<u64> {
+        let now = Instant::now();
+        if self
+            .last_allowed
+            .is_some_and(|last| now.duration_since(last) < self.interval)
+        {
+            self.suppressed += 1;
+            return None;
+        }
+        self.last_allowed = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
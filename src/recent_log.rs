@@ -0,0 +1,49 @@
+//! Keeps the last few log lines around, so the control socket can show them.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use log::{Log, Metadata, Record};
+
+const CAPACITY: usize = 200;
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Passes everything on to env_logger, and remembers what it logged.
+struct RecentLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for RecentLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        let mut recent = RECENT.lock().unwrap();
+        if recent.len() == CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(format!("{:<5} {}", record.level(), record.args()));
+        drop(recent);
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Installs `inner` as the logger.
+pub fn init(inner: env_logger::Logger) {
+    log::set_max_level(inner.filter());
+    log::set_boxed_logger(Box::new(RecentLogger { inner })).expect("logger is only set once");
+}
+
+/// The most recent log lines, oldest first.
+pub fn recent() -> Vec<String> {
+    RECENT.lock().unwrap().iter().cloned().collect()
+}
@@ -0,0 +1,327 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// A count of things that have happened since we started.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A measurement of how things are right now.
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// The upper bounds of histogram buckets, in seconds.
+pub const HISTOGRAM_BOUNDS: [f64; 12] = [
+    0.0005, 0.001, 0.002, 0.004, 0.008, 0.016, 0.032, 0.064, 0.128, 0.256, 0.512, 1.024,
+];
+
+/// How a duration has been distributed over time.
+#[derive(Default)]
+pub struct Histogram {
+    /// How many observations fell in each bucket of [HISTOGRAM_BOUNDS], but not the ones before it.
+    buckets: [AtomicU64; HISTOGRAM_BOUNDS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, seconds: f64) {
+        if let Some(bucket) = HISTOGRAM_BOUNDS.iter().position(|&b| seconds <= b) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add((seconds * 1e6) as u64, Ordering::Relaxed);
+    }
+
+    /// The number of observations at or under each bound in [HISTOGRAM_BOUNDS].
+    pub fn cumulative_buckets(&self) -> [u64; HISTOGRAM_BOUNDS.len()] {
+        let mut total = 0;
+        std::array::from_fn(|i| {
+            total += self.buckets[i].load(Ordering::Relaxed);
+            total
+        })
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+    }
+}
+
+/// How healthy the link is. This lives for the whole process, so it covers restarts too.
+#[derive(Default)]
+pub struct Stats {
+    pub packets_sent: Counter,
+    pub bytes_sent: Counter,
+    pub packets_received: Counter,
+    pub bytes_received: Counter,
+    /// Packets that couldn't be parsed or decoded.
+    pub decode_failures: Counter,
+    /// Packets refused by the source policy.
+    pub rejected: Counter,
+    /// Packets that failed decryption or replay checks.
+    pub auth_failures: Counter,
+    /// Frames that never arrived, and couldn't be recovered.
+    pub frames_lost: Counter,
+    /// Frames rebuilt from parity.
+    pub frames_recovered: Counter,
+    pub frames_duplicate: Counter,
+    /// Frames that arrived after we'd given up on them.
+    pub frames_late: Counter,
+    /// The time between packets arriving.
+    pub packet_interval: Histogram,
+    /// Audio waiting to be handed to PulseAudio, in packets.
+    pub playback_queue: Gauge,
+    /// Audio played on the playback device.
+    pub playback: PlaybackStats,
+    /// How long until written audio is heard, on any device, in seconds.
+    pub playback_latency_histogram: Histogram,
+    /// How long captured audio waits before we read it, in seconds.
+    pub capture_latency: Gauge,
+    /// Levels of the most recent audio, as fractions of full scale.
+    pub capture_peak: Gauge,
+    pub capture_rms: Gauge,
+    /// The gain the AGC is applying to captured audio, in dB.
+    pub capture_agc_gain: Gauge,
+    /// How much quieter echo cancellation makes captured audio, in dB.
+    pub capture_echo_reduction: Gauge,
+    /// Times playback ran dry before we wrote more audio.
+    pub underruns: Counter,
+    /// Times capture filled up before we read from it.
+    pub overruns: Counter,
+    pub restarts: Counter,
+    /// Each stream being received, in the order they're configured.
+    streams: Mutex<Vec<Arc<StreamStats>>>,
+}
+
+/// How audio played on one device is doing.
+#[derive(Default)]
+pub struct PlaybackStats {
+    /// How long until written audio is heard, in seconds.
+    pub latency: Gauge,
+    /// Levels of the most recent audio, as fractions of full scale.
+    pub peak: Gauge,
+    pub rms: Gauge,
+}
+
+/// How one received stream is doing.
+#[derive(Default)]
+pub struct StreamStats {
+    pub name: String,
+    /// How much the time between packets varies, in seconds.
+    pub jitter: Gauge,
+    /// Who we last accepted the stream from.
+    pub peer: Mutex<Option<PeerInfo>>,
+    /// The stream's playback, if it's played on a sink of its own.
+    pub playback: PlaybackStats,
+}
+
+pub struct PeerInfo {
+    pub address: SocketAddr,
+    pub last_packet: Instant,
+    /// What the peer is sending, e.g. `48000 Hz, 2 channels, I24, PCM`.
+    pub format: String,
+}
+
+/// The values of [Stats] at one point in time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    pub decode_failures: u64,
+    pub rejected: u64,
+    pub auth_failures: u64,
+    pub frames_lost: u64,
+    pub frames_recovered: u64,
+    pub frames_duplicate: u64,
+    pub frames_late: u64,
+    pub playback_queue: f64,
+    pub playback_latency: f64,
+    pub capture_latency: f64,
+    pub capture_peak: f64,
+    pub capture_rms: f64,
+    pub capture_agc_gain: f64,
+    pub capture_echo_reduction: f64,
+    pub playback_peak: f64,
+    pub playback_rms: f64,
+    pub underruns: u64,
+    pub overruns: u64,
+    pub restarts: u64,
+    pub streams: Vec<StreamSnapshot>,
+}
+
+/// The values of [StreamStats] at one point in time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamSnapshot {
+    pub name: String,
+    /// Who we last accepted the stream from, if anyone.
+    pub peer: Option<PeerSnapshot>,
+    pub jitter: f64,
+    /// Zero unless the stream is played on a sink of its own.
+    pub playback_latency: f64,
+    pub playback_peak: f64,
+    pub playback_rms: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerSnapshot {
+    pub address: SocketAddr,
+    pub seconds_since_last_packet: f64,
+    pub format: String,
+}
+
+impl Stats {
+    /// Keeps stats for just these streams, in this order, and returns them. Streams that were already being received
+    /// keep theirs.
+    pub fn receive_streams<'a>(
+        &self,
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<Arc<StreamStats>> {
+        let mut streams = self.streams.lock().unwrap();
+        let kept: Vec<_> = names
+            .into_iter()
+            .map(|name| {
+                streams
+                    .iter()
+                    .find(|stream| stream.name == name)
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        Arc::new(StreamStats {
+                            name: name.to_string(),
+                            ..Default::default()
+                        })
+                    })
+            })
+            .collect();
+        streams.clone_from(&kept);
+        kept
+    }
+
+    /// Each stream being received, in the order they're configured.
+    pub fn streams(&self) -> Vec<Arc<StreamStats>> {
+        self.streams.lock().unwrap().clone()
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            packets_sent: self.packets_sent.get(),
+            bytes_sent: self.bytes_sent.get(),
+            packets_received: self.packets_received.get(),
+            bytes_received: self.bytes_received.get(),
+            decode_failures: self.decode_failures.get(),
+            rejected: self.rejected.get(),
+            auth_failures: self.auth_failures.get(),
+            frames_lost: self.frames_lost.get(),
+            frames_recovered: self.frames_recovered.get(),
+            frames_duplicate: self.frames_duplicate.get(),
+            frames_late: self.frames_late.get(),
+            playback_queue: self.playback_queue.get(),
+            playback_latency: self.playback.latency.get(),
+            capture_latency: self.capture_latency.get(),
+            capture_peak: self.capture_peak.get(),
+            capture_rms: self.capture_rms.get(),
+            capture_agc_gain: self.capture_agc_gain.get(),
+            capture_echo_reduction: self.capture_echo_reduction.get(),
+            playback_peak: self.playback.peak.get(),
+            playback_rms: self.playback.rms.get(),
+            underruns: self.underruns.get(),
+            overruns: self.overruns.get(),
+            restarts: self.restarts.get(),
+            streams: self
+                .streams()
+                .iter()
+                .map(|stream| StreamSnapshot {
+                    name: stream.name.clone(),
+                    peer: stream
+                        .peer
+                        .lock()
+                        .unwrap()
+                        .as_ref()
+                        .map(|peer| PeerSnapshot {
+                            address: peer.address,
+                            seconds_since_last_packet: peer.last_packet.elapsed().as_secs_f64(),
+                            format: peer.format.clone(),
+                        }),
+                    jitter: stream.jitter.get(),
+                    playback_latency: stream.playback.latency.get(),
+                    playback_peak: stream.playback.peak.get(),
+                    playback_rms: stream.playback.rms.get(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Logs a summary line every `interval`, forever.
+    pub async fn log_periodically(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick is immediate, and there's nothing to say yet.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let jitter = self
+                .streams()
+                .iter()
+                .map(|stream| format!("{} {:.2} ms", stream.name, stream.jitter.get() * 1000.0))
+                .collect::<Vec<_>>()
+                .join(", ");
+            log::info!(
+                "Sent {} packets ({} KiB), received {} packets ({} KiB); \
+                 lost {}, recovered {}, late {}, duplicate {}; \
+                 rejected {}, auth failures {}, decode failures {}; \
+                 jitter {}; playback queue {:.0} packets, playback latency {:.1} ms, \
+                 capture latency {:.1} ms; underruns {}, overruns {}, restarts {}",
+                self.packets_sent.get(),
+                self.bytes_sent.get() / 1024,
+                self.packets_received.get(),
+                self.bytes_received.get() / 1024,
+                self.frames_lost.get(),
+                self.frames_recovered.get(),
+                self.frames_late.get(),
+                self.frames_duplicate.get(),
+                self.rejected.get(),
+                self.auth_failures.get(),
+                self.decode_failures.get(),
+                jitter,
+                self.playback_queue.get(),
+                self.playback.latency.get() * 1000.0,
+                self.capture_latency.get() * 1000.0,
+                self.underruns.get(),
+                self.overruns.get(),
+                self.restarts.get(),
+            );
+        }
+    }
+}
@@ -1,3 +1,6 @@
+pub(crate) mod crypto;
+pub(crate) mod fec;
+pub(crate) mod opus;
 pub(crate) mod packet;
 pub(crate) mod receiver;
 pub(crate) mod transmitter;
@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use binrw::BinWriterExt;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::vban::packet::{Codec, UserCodec, VbanHeader};
+
+#[derive(Debug, Error)]
+pub enum KeyFileError {
+    #[error("Failed to read {0}: {1}")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("{0} must contain exactly 64 hex digits")]
+    Format(PathBuf),
+}
+
+#[derive(Debug, Error)]
+pub enum OpenError {
+    #[error("Packet is not encrypted")]
+    NotEncrypted,
+    #[error("Packet envelope is truncated")]
+    Truncated,
+    #[error("Packet failed authentication")]
+    Authentication,
+    #[error(
+        "Packet is from a session started at {start}, not after the newest one at {newest}. \
+         It's either replayed, or the peer's clock went back and it lost its session file"
+    )]
+    StaleSession { start: u32, newest: u32 },
+    #[error("Packet is a replay of frame {0}")]
+    Replay(u32),
+}
+
+const SESSION_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+/// The user codec and inner codec bytes, followed by the session ID.
+const PREFIX_SIZE: usize = 2 + SESSION_SIZE;
+
+/// How many bytes the envelope adds to a packet's payload.
+pub const ENVELOPE_OVERHEAD: u32 = (PREFIX_SIZE + TAG_SIZE) as u32;
+
+/// How long a session is used before starting another. A receiver that restarts refuses the last session it saw, so
+/// this is about how long it can take to hear us again.
+const SESSION_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Identifies one run of a transmitter, so that nonces are never reused across restarts.
+/// The first four bytes are the start time, which lets the receiver refuse any session that isn't newer than the last.
+type SessionId = [u8; SESSION_SIZE];
+
+/// Loads a pre-shared key, written as 64 hex digits.
+pub fn load_key(path: &Path) -> Result<Key, KeyFileError> {
+    let text = std::fs::read_to_string(path).map_err(|e| KeyFileError::Read(path.into(), e))?;
+    let text = text.trim().as_bytes();
+    if text.len() != 64 {
+        return Err(KeyFileError::Format(path.into()));
+    }
+    let mut key = Key::default();
+    // from_str_radix would also take a sign.
+    if !text.iter().all(u8::is_ascii_hexdigit) {
+        return Err(KeyFileError::Format(path.into()));
+    }
+    for (byte, digits) in key.iter_mut().zip(text.chunks_exact(2)) {
+        let digits = std::str::from_utf8(digits).expect("hex digits are ASCII");
+        *byte = u8::from_str_radix(digits, 16).expect("hex digits always parse");
+    }
+    Ok(key)
+}
+
+fn new_session_id(start: u32) -> SessionId {
+    let mut session = [0u8; SESSION_SIZE];
+    session[..4].copy_from_slice(&start.to_be_bytes());
+    getrandom::getrandom(&mut session[4..]).expect("should be able to get random bytes");
+    session
+}
+
+fn session_start(session: &SessionId) -> u32 {
+    u32::from_be_bytes(session[..4].try_into().unwrap())
+}
+
+fn nonce(session: &SessionId, frame_counter: u32) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[..SESSION_SIZE].copy_from_slice(session);
+    nonce[SESSION_SIZE..].copy_from_slice(&frame_counter.to_le_bytes());
+    nonce
+}
+
+/// The header and the envelope prefix are authenticated, but not encrypted.
+fn associated_data(header: &VbanHeader, prefix: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(28 + PREFIX_SIZE);
+    Cursor::new(&mut aad)
+        .write_le(header)
+        .expect("should always be able to write to a Vec");
+    aad.extend_from_slice(prefix);
+    aad
+}
+
+/// Encrypts outgoing packets.
+pub struct Sealer {
+    cipher: ChaCha20Poly1305,
+    sessions: Arc<SessionStore>,
+    session: SessionId,
+    started: Instant,
+    last_frame_counter: Option<u32>,
+}
+
+impl Sealer {
+    pub fn new(key: &Key, sessions: Arc<SessionStore>) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(key),
+            session: new_session_id(sessions.next_sent_start()),
+            started: Instant::now(),
+            sessions,
+            last_frame_counter: None,
+        }
+    }
+
+    /// Wraps `data` in an envelope, and marks the header as carrying one.
+    pub fn seal(&mut self, header: &mut VbanHeader, data: &[u8]) -> Vec<u8> {
+        // The frame counter is part of the nonce, so start a new session if it wraps.
+        if self
+            .last_frame_counter
+            .is_some_and(|last| header.frame_counter <= last)
+            || self.started.elapsed() >= SESSION_LIFETIME
+        {
+            self.session = new_session_id(self.sessions.next_sent_start());
+            self.started = Instant::now();
+        }
+        self.last_frame_counter = Some(header.frame_counter);
+
+        let inner_codec = header.codec;
+        header.codec = Codec::User;
+        let mut envelope = Vec::with_capacity(data.len() + ENVELOPE_OVERHEAD as usize);
+        envelope.push(u8::from(UserCodec::Envelope));
+        envelope.push(u8::from(inner_codec));
+        envelope.extend_from_slice(&self.session);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce(&self.session, header.frame_counter),
+                Payload {
+                    msg: data,
+                    aad: &associated_data(header, &envelope),
+                },
+            )
+            .expect("encryption should not fail");
+        envelope.extend_from_slice(&ciphertext);
+        envelope
+    }
+}
+
+/// The newest session started on each side, saved so that sessions only ever get newer, even across restarts.
+#[derive(Default)]
+pub struct SessionStore {
+    state: Mutex<StoreState>,
+}
+
+#[derive(Default)]
+struct StoreState {
+    /// Where `saved` is kept, once it's known.
+    path: Option<PathBuf>,
+    saved: SavedSessions,
+    /// The session being received on each stream.
+    current: HashMap<String, Session>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct SavedSessions {
+    /// When the newest session we sent started.
+    sent: u32,
+    /// When the newest session accepted on each stream started.
+    received: HashMap<String, u32>,
+}
+
+impl SessionStore {
+    /// Keeps sessions in `path`, taking the newest of what's there and what's already known.
+    pub fn use_file(&self, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        if state.path.as_deref() == Some(path) {
+            return;
+        }
+        let saved = match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                log::warn!("Ignoring unreadable session file {}: {}", path.display(), e);
+                SavedSessions::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => SavedSessions::default(),
+            Err(e) => {
+                log::warn!("Couldn't read session file {}: {}", path.display(), e);
+                SavedSessions::default()
+            }
+        };
+        state.saved.sent = state.saved.sent.max(saved.sent);
+        for (stream, start) in saved.received {
+            let newest = state.saved.received.entry(stream).or_default();
+            *newest = (*newest).max(start);
+        }
+        state.path = Some(path.to_path_buf());
+        state.save();
+    }
+
+    pub fn opener(self: &Arc<Self>, key: &Key, stream_name: &str) -> Opener {
+        Opener {
+            cipher: ChaCha20Poly1305::new(key),
+            stream_name: stream_name.to_string(),
+            sessions: Arc::clone(self),
+        }
+    }
+
+    /// When a new session we send starts: now, unless that isn't after the last one, for example if the clock went
+    /// back.
+    fn next_sent_start(&self) -> u32 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time should be after the epoch")
+            .as_secs() as u32;
+        let mut state = self.state.lock().unwrap();
+        let start = now.max(state.saved.sent.saturating_add(1));
+        state.saved.sent = start;
+        state.save();
+        start
+    }
+}
+
+impl StoreState {
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let text = serde_json::to_string(&self.saved).expect("sessions always serialize");
+        // Write it whole and then move it into place, so a crash can't leave half of it.
+        let temporary = path.with_extension("tmp");
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| std::fs::write(&temporary, text))
+            .and_then(|()| std::fs::rename(&temporary, path));
+        if let Err(e) = result {
+            log::warn!("Couldn't save session file {}: {}", path.display(), e);
+        }
+    }
+}
+
+struct Session {
+    id: SessionId,
+    window: ReplayWindow,
+}
+
+/// Decrypts incoming packets, and refuses replayed ones.
+pub struct Opener {
+    cipher: ChaCha20Poly1305,
+    stream_name: String,
+    sessions: Arc<SessionStore>,
+}
+
+impl Opener {
+    /// Unwraps an envelope, and restores the header's codec to the one it carried.
+    pub fn open(&mut self, header: &mut VbanHeader, data: &[u8]) -> Result<Vec<u8>, OpenError> {
+        if !matches!(header.codec, Codec::User)
+            || data.first().copied() != Some(u8::from(UserCodec::Envelope))
+        {
+            return Err(OpenError::NotEncrypted);
+        }
+        if data.len() < ENVELOPE_OVERHEAD as usize {
+            return Err(OpenError::Truncated);
+        }
+        let (prefix, ciphertext) = data.split_at(PREFIX_SIZE);
+        let session: SessionId = prefix[2..].try_into().unwrap();
+        let plaintext = self
+            .cipher
+            .decrypt(
+                &nonce(&session, header.frame_counter),
+                Payload {
+                    msg: ciphertext,
+                    aad: &associated_data(header, prefix),
+                },
+            )
+            .map_err(|_| OpenError::Authentication)?;
+
+        let frame_counter = header.frame_counter;
+        let state = &mut *self.sessions.state.lock().unwrap();
+        match state.current.get_mut(&self.stream_name) {
+            Some(current) if current.id == session => {
+                if !current.window.accept(frame_counter) {
+                    return Err(OpenError::Replay(frame_counter));
+                }
+            }
+            _ => {
+                // Only a newer session can take over, so no session can ever come back.
+                let start = session_start(&session);
+                let newest = state.saved.received.get(&self.stream_name).copied();
+                if let Some(newest) = newest.filter(|&newest| start <= newest) {
+                    return Err(OpenError::StaleSession { start, newest });
+                }
+                log::debug!(
+                    "Peer started a new encrypted session on {}",
+                    self.stream_name
+                );
+                let mut window = ReplayWindow::default();
+                window.accept(frame_counter);
+                state.current.insert(
+                    self.stream_name.clone(),
+                    Session {
+                        id: session,
+                        window,
+                    },
+                );
+                state.saved.received.insert(self.stream_name.clone(), start);
+                state.save();
+            }
+        }
+
+        header.codec = Codec::from(prefix[1]);
+        Ok(plaintext)
+    }
+}
+
+/// Tracks which recent frame counters have been seen.
+#[derive(Default)]
+struct ReplayWindow {
+    /// The highest frame counter seen, if any.
+    highest: Option<u32>,
+    /// Bit `n` is set if `highest - n` has been seen.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Records the frame counter, returning `false` if it was already seen or is too old to tell.
+    fn accept(&mut self, frame_counter: u32) -> bool {
+        let Some(highest) = self.highest else {
+            self.highest = Some(frame_counter);
+            self.seen = 1;
+            return true;
+        };
+        if frame_counter > highest {
+            let shift = frame_counter - highest;
+            self.seen = if shift < 64 { self.seen << shift } else { 0 } | 1;
+            self.highest = Some(frame_counter);
+            return true;
+        }
+        let age = highest - frame_counter;
+        if age >= 64 || self.seen & (1 << age) != 0 {
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+}
@@ -0,0 +1,386 @@
+//! Forward error correction using XOR parity.
+//!
+//! After every group of audio packets, the transmitter sends a [UserCodec::Parity] packet holding the XOR of the
+//! group's payloads. If exactly one packet of the group is lost, the receiver can rebuild it from the others.
+//! Parity packets use frame counters of their own, right after the group they cover.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::stats::Stats;
+use crate::vban::packet::{Codec, UserCodec, VbanHeader, VbanPacket};
+
+/// The user codec, group size, first frame counter, codec, samples and length that precede the parity data.
+pub const PARITY_OVERHEAD: u32 = 1 + 1 + 4 + 1 + 1 + 2;
+/// How many frames past a gap we hold packets for, waiting to fill it.
+const WINDOW: u32 = 64;
+
+/// Computes parity packets for outgoing audio.
+pub struct ParityEncoder {
+    group_size: u8,
+    /// The number of packets in the current group so far.
+    count: u8,
+    first: u32,
+    codec: Codec,
+    samples_xor: u8,
+    len_xor: u16,
+    data_xor: Vec<u8>,
+}
+
+impl ParityEncoder {
+    pub fn new(group_size: u8) -> Self {
+        Self {
+            group_size: group_size.max(1),
+            count: 0,
+            first: 0,
+            codec: Codec::PCM,
+            samples_xor: 0,
+            len_xor: 0,
+            data_xor: Vec::new(),
+        }
+    }
+
+    /// Adds a sent packet to the current group. When the group is full, returns the payload of its parity packet.
+    pub fn add(&mut self, header: &VbanHeader, data: &[u8]) -> Option<Vec<u8>> {
+        if self.count == 0 {
+            self.first = header.frame_counter;
+            self.codec = header.codec;
+            self.samples_xor = 0;
+            self.len_xor = 0;
+            self.data_xor.clear();
+        }
+        self.count += 1;
+        self.samples_xor ^= header.samples_per_frame;
+        self.len_xor ^= data.len() as u16;
+        xor_into(&mut self.data_xor, data);
+        if self.count < self.group_size {
+            return None;
+        }
+
+        self.count = 0;
+        let mut payload = Vec::with_capacity(PARITY_OVERHEAD as usize + self.data_xor.len());
+        payload.push(u8::from(UserCodec::Parity));
+        payload.push(self.group_size);
+        payload.extend_from_slice(&self.first.to_le_bytes());
+        payload.push(u8::from(self.codec));
+        payload.push(self.samples_xor);
+        payload.extend_from_slice(&self.len_xor.to_le_bytes());
+        payload.extend_from_slice(&self.data_xor);
+        Some(payload)
+    }
+}
+
+fn xor_into(acc: &mut Vec<u8>, data: &[u8]) {
+    if acc.len() < data.len() {
+        acc.resize(data.len(), 0);
+    }
+    acc.iter_mut().zip(data).for_each(|(a, b)| *a ^= b);
+}
+
+/// A decoded parity packet.
+struct Parity {
+    group_size: u8,
+    first: u32,
+    codec: Codec,
+    samples_xor: u8,
+    len_xor: u16,
+    data_xor: Vec<u8>,
+}
+
+impl Parity {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < PARITY_OVERHEAD as usize || data[0] != u8::from(UserCodec::Parity) {
+            return None;
+        }
+        Some(Self {
+            group_size: data[1],
+            first: u32::from_le_bytes(data[2..6].try_into().unwrap()),
+            codec: Codec::from(data[6]),
+            samples_xor: data[7],
+            len_xor: u16::from_le_bytes(data[8..10].try_into().unwrap()),
+            data_xor: data[PARITY_OVERHEAD as usize..].to_vec(),
+        })
+    }
+
+    fn covers(&self, frame_counter: u32) -> bool {
+        frame_counter.wrapping_sub(self.first) < u32::from(self.group_size)
+    }
+
+    /// The frame counter the parity packet itself was sent with.
+    fn parity_frame(&self) -> u32 {
+        self.first.wrapping_add(u32::from(self.group_size))
+    }
+
+    /// Groups follow each other, so this says where a frame falls in its group, even in groups we haven't seen.
+    /// The parity packet is at `group_size`.
+    fn position(&self, frame_counter: u32) -> u32 {
+        let stride = i64::from(self.group_size) + 1;
+        let relative = frame_counter.wrapping_sub(self.first) as i32;
+        i64::from(relative).rem_euclid(stride) as u32
+    }
+}
+
+pub fn is_parity(packet: &VbanPacket) -> bool {
+    matches!(packet.header.codec, Codec::User)
+        && packet.data.first() == Some(&u8::from(UserCodec::Parity))
+}
+
+/// What the receiver should play next.
+pub enum Received {
+    Packet(VbanPacket),
+    /// A packet was lost, and couldn't be recovered.
+    Lost,
+}
+
+enum Slot {
+    Data(VbanPacket),
+    Parity,
+}
+
+/// Puts incoming packets back in order, notes which were lost, and recovers what it can from parity packets.
+/// Without parity packets, this only detects loss, and adds no delay.
+pub struct FecDecoder {
+    stats: Arc<Stats>,
+    /// The next frame counter to release.
+    next: Option<u32>,
+    /// Packets waiting to be released. Index 0 is `next`.
+    pending: VecDeque<Option<Slot>>,
+    /// Packets recently released, kept to rebuild others in their group.
+    released: VecDeque<VbanPacket>,
+    parities: VecDeque<Parity>,
+}
+
+impl FecDecoder {
+    pub fn new(stats: Arc<Stats>) -> Self {
+        Self {
+            stats,
+            next: None,
+            pending: VecDeque::new(),
+            released: VecDeque::new(),
+            parities: VecDeque::new(),
+        }
+    }
+
+    /// Takes a packet off the network, and returns everything that's ready to play, in order.
+    pub fn push(&mut self, packet: VbanPacket) -> Vec<Received> {
+        let frame_counter = packet.header.frame_counter;
+        let next = *self.next.get_or_insert(frame_counter);
+        let behind = next.wrapping_sub(frame_counter);
+        if behind != 0 && behind <= WINDOW {
+            // We've already moved past this one.
+            if self.find(frame_counter).is_some() {
+                self.stats.frames_duplicate.inc();
+            } else {
+                self.stats.frames_late.inc();
+            }
+            return Vec::new();
+        }
+        let mut ready = Vec::new();
+        let offset = frame_counter.wrapping_sub(next);
+        if offset >= WINDOW {
+            // Either the gap is too long to wait for, or the peer restarted. Give up on the gap and start over.
+            let gaps = self.pending.iter().filter(|s| s.is_none()).count();
+            self.stats.frames_lost.add(gaps as u64);
+            ready.extend(self.pending.drain(..).filter_map(|slot| match slot {
+                Some(Slot::Data(packet)) => Some(Received::Packet(packet)),
+                _ => None,
+            }));
+            self.released.clear();
+            self.next = Some(frame_counter);
+            return self.store_and_release(0, packet, ready);
+        }
+        self.store_and_release(offset as usize, packet, ready)
+    }
+
+    fn store_and_release(
+        &mut self,
+        offset: usize,
+        packet: VbanPacket,
+        mut ready: Vec<Received>,
+    ) -> Vec<Received> {
+        let slot = if is_parity(&packet) {
+            if let Some(parity) = Parity::parse(&packet.data) {
+                self.parities.push_back(parity);
+                if self.parities.len() > WINDOW as usize {
+                    self.parities.pop_front();
+                }
+            }
+            Slot::Parity
+        } else {
+            Slot::Data(packet)
+        };
+        if self.pending.len() <= offset {
+            self.pending.resize_with(offset + 1, || None);
+        }
+        if self.pending[offset].is_none() {
+            self.pending[offset] = Some(slot);
+        } else {
+            self.stats.frames_duplicate.inc();
+        }
+
+        while let Some(front) = self.pending.front_mut() {
+            let next = self.next.expect("next is set when a packet is pushed");
+            match front.take() {
+                Some(Slot::Data(packet)) => {
+                    self.release(&packet);
+                    ready.push(Received::Packet(packet));
+                }
+                Some(Slot::Parity) => {}
+                None => {
+                    if let Some(packet) = self.recover(next) {
+                        self.stats.frames_recovered.inc();
+                        log::debug!("Recovered lost frame {} from parity", next);
+                        self.release(&packet);
+                        ready.push(Received::Packet(packet));
+                    } else if self.is_parity_frame(next) {
+                        // A lost parity packet doesn't cost any audio.
+                    } else if self.should_give_up(next) {
+                        self.stats.frames_lost.inc();
+                        ready.push(Received::Lost);
+                    } else {
+                        break;
+                    }
+                }
+            }
+            self.pending.pop_front();
+            self.next = Some(next.wrapping_add(1));
+        }
+        ready
+    }
+
+    fn release(&mut self, packet: &VbanPacket) {
+        self.released.push_back(packet.clone());
+        if self.released.len() > u8::MAX as usize {
+            self.released.pop_front();
+        }
+    }
+
+    fn is_parity_frame(&self, frame_counter: u32) -> bool {
+        self.parities
+            .iter()
+            .any(|p| p.parity_frame() == frame_counter)
+            || self
+                .parities
+                .back()
+                .is_some_and(|p| p.position(frame_counter) == u32::from(p.group_size))
+    }
+
+    /// Is there no hope left of filling this gap?
+    fn should_give_up(&self, frame_counter: u32) -> bool {
+        let Some(latest) = self.parities.back() else {
+            // Without parity, there's nothing to wait for.
+            return true;
+        };
+        // Once the parity packet for this frame's group has arrived or been skipped, it can't be recovered.
+        let to_parity = u32::from(latest.group_size) - latest.position(frame_counter);
+        self.pending.len() > to_parity as usize
+    }
+
+    /// Rebuilds a lost packet, if its group's parity and all its other packets are here.
+    fn recover(&self, frame_counter: u32) -> Option<VbanPacket> {
+        let parity = self.parities.iter().find(|p| p.covers(frame_counter))?;
+        let mut samples = parity.samples_xor;
+        let mut len = parity.len_xor;
+        let mut data = parity.data_xor.clone();
+        let mut template = None;
+        for i in 0..u32::from(parity.group_size) {
+            let member = parity.first.wrapping_add(i);
+            if member == frame_counter {
+                continue;
+            }
+            let packet = self.find(member)?;
+            samples ^= packet.header.samples_per_frame;
+            len ^= packet.data.len() as u16;
+            xor_into(&mut data, &packet.data);
+            template = Some(&packet.header);
+        }
+        data.truncate(len as usize);
+        let header = VbanHeader {
+            samples_per_frame: samples,
+            codec: parity.codec,
+            frame_counter,
+            ..template?.clone()
+        };
+        let packet = VbanPacket { header, data };
+        // The parity packet sets the codec and the data, so a forged one could rebuild anything.
+        if is_parity(&packet) {
+            return None;
+        }
+        Some(packet)
+    }
+
+    fn find(&self, frame_counter: u32) -> Option<&VbanPacket> {
+        let next = self.next?;
+        let offset = frame_counter.wrapping_sub(next);
+        if offset < self.pending.len() as u32 {
+            return match &self.pending[offset as usize] {
+                Some(Slot::Data(packet)) => Some(packet),
+                _ => None,
+            };
+        }
+        self.released
+            .iter()
+            .rev()
+            .find(|p| p.header.frame_counter == frame_counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use binrw::BinReaderExt;
+
+    use super::*;
+
+    fn packet(frame_counter: u32, codec: Codec, data: &[u8]) -> VbanPacket {
+        let mut bytes = b"VBAN".to_vec();
+        // 48 kHz, 1 sample, 2 channels, 16-bit.
+        bytes.extend_from_slice(&[3, 0, 1, 0x01 | u8::from(codec)]);
+        bytes.extend_from_slice(b"test\0\0\0\0\0\0\0\0\0\0\0\0");
+        bytes.extend_from_slice(&frame_counter.to_le_bytes());
+        bytes.extend_from_slice(data);
+        Cursor::new(bytes).read_le().unwrap()
+    }
+
+    #[test]
+    fn recovers_a_lost_packet() {
+        let frames = [
+            packet(0, Codec::PCM, &[1, 2, 3, 4]),
+            packet(1, Codec::PCM, &[5, 6]),
+        ];
+        let mut encoder = ParityEncoder::new(2);
+        assert_eq!(encoder.add(&frames[0].header, &frames[0].data), None);
+        let parity = encoder.add(&frames[1].header, &frames[1].data).unwrap();
+
+        let stats = Arc::new(Stats::default());
+        let mut decoder = FecDecoder::new(Arc::clone(&stats));
+        let released = decoder.push(frames[0].clone());
+        assert!(matches!(released[..], [Received::Packet(_)]));
+        // Frame 1 is lost.
+        let released = decoder.push(packet(2, Codec::User, &parity));
+        let [Received::Packet(recovered)] = &released[..] else {
+            panic!("frame 1 should have been recovered");
+        };
+        assert_eq!(recovered.header.frame_counter, 1);
+        assert_eq!(recovered.data, frames[1].data);
+        assert_eq!(stats.frames_recovered.get(), 1);
+    }
+
+    #[test]
+    fn does_not_recover_parity_packets() {
+        let mut decoder = FecDecoder::new(Arc::new(Stats::default()));
+        let released = decoder.push(packet(0, Codec::PCM, &[0, 0]));
+        assert!(matches!(released[..], [Received::Packet(_)]));
+
+        // Covers frames 0 and 1, and would rebuild frame 1 as another parity packet.
+        let mut parity = vec![u8::from(UserCodec::Parity), 2];
+        parity.extend_from_slice(&0u32.to_le_bytes());
+        parity.push(u8::from(Codec::User));
+        parity.push(0);
+        parity.extend_from_slice(&0u16.to_le_bytes());
+        parity.extend_from_slice(&[u8::from(UserCodec::Parity), 0]);
+        let released = decoder.push(packet(2, Codec::User, &parity));
+        assert!(matches!(released[..], [Received::Lost]));
+    }
+}
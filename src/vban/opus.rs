@@ -0,0 +1,239 @@
+//! Opus compression, carried as a [UserCodec::Opus] payload.
+//! Support is only compiled in with the `opus` feature, as it needs libopus.
+
+use thiserror::Error;
+
+use crate::audio_engine::AudioFormat;
+
+pub use imp::{OpusDecoder, OpusEncoder};
+
+#[derive(Debug, Error)]
+pub enum OpusError {
+    #[cfg(not(feature = "opus"))]
+    #[error("Opus support was not compiled in, enable the `opus` feature")]
+    Unsupported,
+    #[error("Opus frames must be 2.5, 5, 10, 20, 40 or 60 ms long, not {0} ms")]
+    InvalidFrameDuration(f32),
+    #[error("Opus can only send {FORMAT}, not {0}")]
+    UnsupportedFormat(AudioFormat),
+    #[cfg(feature = "opus")]
+    #[error("Packet is not Opus")]
+    NotOpus,
+    #[cfg(feature = "opus")]
+    #[error("Opus error: {0}")]
+    Opus(#[from] audiopus::Error),
+}
+
+/// Opus only runs at 48 kHz here, as that's what we send.
+const SAMPLE_RATE: u32 = 48000;
+
+/// What decoded Opus comes out as.
+pub const FORMAT: AudioFormat = AudioFormat {
+    rate: SAMPLE_RATE,
+    channels: 2,
+};
+
+/// Checks that captured audio is in the format Opus sends.
+fn check_format(format: AudioFormat) -> Result<(), OpusError> {
+    if format == FORMAT {
+        Ok(())
+    } else {
+        Err(OpusError::UnsupportedFormat(format))
+    }
+}
+
+/// The number of samples per channel in a frame of this duration, if Opus supports it.
+fn frame_samples(frame_ms: f32) -> Result<usize, OpusError> {
+    let samples = (SAMPLE_RATE as f32 * frame_ms / 1000.0) as usize;
+    if [120, 240, 480, 960, 1920, 2880].contains(&samples) {
+        Ok(samples)
+    } else {
+        Err(OpusError::InvalidFrameDuration(frame_ms))
+    }
+}
+
+#[cfg(feature = "opus")]
+mod imp {
+    use audiopus::coder::{Decoder, Encoder};
+    use audiopus::{Application, Bitrate, Channels, SampleRate};
+
+    use super::*;
+    use crate::config::global::OpusConfig;
+    use crate::pcm::{f32_to_s24le, s24le_to_f32};
+    use crate::vban::packet::UserCodec;
+
+    const CHANNELS: usize = 2;
+    /// The longest packet Opus can produce or conceal, 120 ms.
+    const MAX_FRAME_SAMPLES: usize = 5760;
+    /// Don't try to conceal more than this many packets in a row. Past this, just skip ahead.
+    const MAX_CONCEALED_PACKETS: u32 = 8;
+
+    /// Re-frames captured PCM into Opus packets.
+    pub struct OpusEncoder {
+        encoder: Encoder,
+        frame_samples: usize,
+        max_packet_size: usize,
+        pending: Vec<f32>,
+    }
+
+    impl OpusEncoder {
+        /// Creates an encoder for audio in `format`, whose packets, including the user codec byte, fit in
+        /// `max_packet_size`.
+        pub fn new(
+            config: &OpusConfig,
+            format: AudioFormat,
+            max_packet_size: usize,
+        ) -> Result<Self, OpusError> {
+            check_format(format)?;
+            let frame_samples = frame_samples(config.frame_ms)?;
+            let mut encoder =
+                Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)?;
+            encoder.set_bitrate(Bitrate::BitsPerSecond(config.bitrate as i32))?;
+            encoder.set_inband_fec(config.fec)?;
+            encoder.set_packet_loss_perc(config.expected_loss)?;
+            Ok(Self {
+                encoder,
+                frame_samples,
+                max_packet_size,
+                pending: Vec::new(),
+            })
+        }
+
+        /// How many samples each packet holds.
+        pub fn samples_per_frame(&self) -> usize {
+            self.frame_samples
+        }
+
+        /// Buffers signed 24-bit PCM, and returns a payload for each complete frame.
+        pub fn encode(&mut self, pcm: &[u8]) -> Result<Vec<Vec<u8>>, OpusError> {
+            s24le_to_f32(pcm, &mut self.pending);
+            let frame_len = self.frame_samples * CHANNELS;
+            let mut payloads = Vec::new();
+            while self.pending.len() >= frame_len {
+                let mut payload = vec![0u8; self.max_packet_size];
+                payload[0] = u8::from(UserCodec::Opus);
+                let len = self
+                    .encoder
+                    .encode_float(&self.pending[..frame_len], &mut payload[1..])?;
+                payload.truncate(1 + len);
+                payloads.push(payload);
+                self.pending.drain(..frame_len);
+            }
+            Ok(payloads)
+        }
+    }
+
+    /// Decodes Opus packets to PCM, concealing lost packets.
+    pub struct OpusDecoder {
+        decoder: Decoder,
+        /// How many packets were lost since the last one we decoded.
+        lost: u32,
+        output: Vec<f32>,
+    }
+
+    impl OpusDecoder {
+        pub fn new() -> Result<Self, OpusError> {
+            Ok(Self {
+                decoder: Decoder::new(SampleRate::Hz48000, Channels::Stereo)?,
+                lost: 0,
+                output: vec![0.0; MAX_FRAME_SAMPLES * CHANNELS],
+            })
+        }
+
+        /// Decodes a payload to signed 24-bit PCM, or notes that a packet was lost if given `None`.
+        /// Lost packets are covered for when the next one arrives, as it may carry a copy of the last one.
+        pub fn decode(&mut self, payload: Option<&[u8]>) -> Result<Vec<u8>, OpusError> {
+            let Some(payload) = payload else {
+                self.lost += 1;
+                return Ok(Vec::new());
+            };
+            let Some((&user_codec, packet)) = payload.split_first() else {
+                return Err(OpusError::NotOpus);
+            };
+            if user_codec != u8::from(UserCodec::Opus) {
+                return Err(OpusError::NotOpus);
+            }
+
+            let mut pcm = Vec::new();
+            let lost = std::mem::take(&mut self.lost).min(MAX_CONCEALED_PACKETS);
+            // Before the first packet, we don't know how long a frame is, so there's nothing to conceal with.
+            let frame_len = self.decoder.last_packet_duration()? as usize * CHANNELS;
+            if lost > 0 && frame_len > 0 {
+                for _ in 1..lost {
+                    let n = self.decoder.decode_float(
+                        None,
+                        (&mut self.output[..frame_len]).try_into()?,
+                        false,
+                    )?;
+                    f32_to_s24le(&self.output[..n * CHANNELS], &mut pcm);
+                }
+                // The packet we have may carry a low-quality copy of the one just before it.
+                let n = self.decoder.decode_float(
+                    Some(packet.try_into()?),
+                    (&mut self.output[..frame_len]).try_into()?,
+                    true,
+                )?;
+                f32_to_s24le(&self.output[..n * CHANNELS], &mut pcm);
+            }
+
+            let n = self.decoder.decode_float(
+                Some(packet.try_into()?),
+                (&mut self.output[..]).try_into()?,
+                false,
+            )?;
+            f32_to_s24le(&self.output[..n * CHANNELS], &mut pcm);
+            Ok(pcm)
+        }
+    }
+}
+
+#[cfg(not(feature = "opus"))]
+mod imp {
+    use std::sync::Once;
+
+    use super::*;
+    use crate::config::global::OpusConfig;
+
+    pub enum OpusEncoder {}
+
+    impl OpusEncoder {
+        pub fn new(
+            config: &OpusConfig,
+            format: AudioFormat,
+            _max_packet_size: usize,
+        ) -> Result<Self, OpusError> {
+            // Still report bad config, so it's caught before someone turns the feature on.
+            check_format(format)?;
+            frame_samples(config.frame_ms)?;
+            Err(OpusError::Unsupported)
+        }
+
+        pub fn samples_per_frame(&self) -> usize {
+            match *self {}
+        }
+
+        pub fn encode(&mut self, _pcm: &[u8]) -> Result<Vec<Vec<u8>>, OpusError> {
+            match *self {}
+        }
+    }
+
+    pub enum OpusDecoder {}
+
+    impl OpusDecoder {
+        pub fn new() -> Result<Self, OpusError> {
+            // The rejections that follow are rate-limited warnings among others, so say why once, loudly.
+            static REPORTED: Once = Once::new();
+            REPORTED.call_once(|| {
+                log::error!(
+                    "Received Opus audio, but Opus support was not compiled in. \
+                     Build with `--features opus` to play it"
+                )
+            });
+            Err(OpusError::Unsupported)
+        }
+
+        pub fn decode(&mut self, _payload: Option<&[u8]>) -> Result<Vec<u8>, OpusError> {
+            match *self {}
+        }
+    }
+}
@@ -15,6 +15,8 @@ pub enum VbanPacketError {
     TooManySamplesPerFrame,
     #[error("Too many channels")]
     TooManyChannels,
+    #[error("Unknown user codec: {0}")]
+    UnknownUserCodec(u8),
 }
 
 /// A VB-Audio Network packet.
@@ -29,6 +31,39 @@ pub struct VbanPacket {
     pub data: Vec<u8>,
 }
 
+impl VbanPacket {
+    /// The kind of payload, if this uses [Codec::User].
+    pub fn user_codec(&self) -> Option<UserCodec> {
+        match self.header.codec {
+            Codec::User => UserCodec::try_from(*self.data.first()?).ok(),
+            _ => None,
+        }
+    }
+
+    /// Describes the audio format, e.g. `48000 Hz, 2 channels, I24, PCM`.
+    pub fn describe_format(&self) -> String {
+        let header = &self.header;
+        let rate = match header.sample_rate.get_rate_if_known() {
+            Some(rate) => format!("{} Hz", rate),
+            None => "unknown rate".to_string(),
+        };
+        let codec = match header.codec {
+            Codec::User => match self.user_codec() {
+                Some(user_codec) => format!("{:?}", user_codec),
+                None => "unknown user codec".to_string(),
+            },
+            codec => format!("{:?}", codec),
+        };
+        format!(
+            "{}, {} channels, {:?}, {}",
+            rate,
+            u32::from(header.channels) + 1,
+            header.data_type,
+            codec
+        )
+    }
+}
+
 /// A VB-Audio Network packet header.
 /// This is biased towards only really working with [SubProtocol::Audio].
 #[binrw]
@@ -67,7 +102,7 @@ pub struct VbanHeader {
 impl VbanHeader {
     pub fn next(self) -> VbanHeader {
         VbanHeader {
-            frame_counter: self.frame_counter + 1,
+            frame_counter: self.frame_counter.wrapping_add(1),
             ..self
         }
     }
@@ -263,6 +298,20 @@ pub enum DataType {
     I10,
 }
 
+impl DataType {
+    /// The size of a sample, in bytes, if it's a whole number of them.
+    pub fn sample_size(self) -> Option<usize> {
+        match self {
+            DataType::U8 => Some(1),
+            DataType::I16 => Some(2),
+            DataType::I24 => Some(3),
+            DataType::I32 | DataType::F32 => Some(4),
+            DataType::F64 => Some(8),
+            DataType::I12 | DataType::I10 => None,
+        }
+    }
+}
+
 impl From<DataType> for u8 {
     fn from(data_type: DataType) -> Self {
         match data_type {
@@ -344,3 +393,36 @@ impl From<u8> for Codec {
         }
     }
 }
+
+/// Identifies the format of a [Codec::User] payload, which is stored in its first byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCodec {
+    /// An encrypted envelope around another payload.
+    Envelope = 0x01,
+    /// Opus-compressed audio.
+    Opus = 0x02,
+    /// XOR parity over a group of packets, for forward error correction.
+    Parity = 0x03,
+    /// Sent instead of audio while the sender's noise gate is shut.
+    Keepalive = 0x04,
+}
+
+impl From<UserCodec> for u8 {
+    fn from(user_codec: UserCodec) -> Self {
+        user_codec as u8
+    }
+}
+
+impl TryFrom<u8> for UserCodec {
+    type Error = VbanPacketError;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0x01 => Ok(UserCodec::Envelope),
+            0x02 => Ok(UserCodec::Opus),
+            0x03 => Ok(UserCodec::Parity),
+            0x04 => Ok(UserCodec::Keepalive),
+            _ => Err(VbanPacketError::UnknownUserCodec(v)),
+        }
+    }
+}
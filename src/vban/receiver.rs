@@ -1,13 +1,24 @@
+use std::fmt::Display;
 use std::io::Cursor;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::asciistackstr::AsciiStackString;
+use crate::audio_engine::{Audio, AudioFormat};
 use binrw::BinReaderExt;
 use thiserror::Error;
 use tokio::net::UdpSocket;
 
-use crate::vban::packet::{Codec, DataType, SampleRate, VbanPacket};
+use crate::config::source_policy::SourcePolicy;
+use crate::events::{Event, Events};
+use crate::pcm::to_s24le;
+use crate::ratelimit::RateLimit;
+use crate::stats::{Counter, PeerInfo, Stats, StreamStats};
+use crate::vban::crypto::Opener;
+use crate::vban::fec::{is_parity, FecDecoder, Received};
+use crate::vban::opus::{self, OpusDecoder, OpusError};
+use crate::vban::packet::{Codec, UserCodec, VbanPacket, VbanPacketError};
 
 #[derive(Debug, Error)]
 pub enum ReceiverError {
@@ -17,43 +28,405 @@ pub enum ReceiverError {
     AudioChannelBroken,
 }
 
+#[derive(Debug, Error)]
+enum PayloadError {
+    #[error("Packet is encrypted, but no key is configured")]
+    Encrypted,
+    #[error("{0}")]
+    Packet(#[from] VbanPacketError),
+    #[error("{0}")]
+    Opus(#[from] OpusError),
+    #[error("Can't play {0}")]
+    UnsupportedFormat(String),
+    #[error("Parity packet where audio was expected")]
+    UnexpectedParity,
+}
+
+/// What a packet turns into.
+enum Payload {
+    Samples(AudioFormat, Vec<u8>),
+    Idle,
+}
+
 pub struct Receiver {
-    pub stream_name: AsciiStackString<16>,
-    pub recv_address: IpAddr,
-    pub audio_out: tokio::sync::mpsc::Sender<Vec<u8>>,
+    /// The streams to accept. Packets for anything else are ignored.
+    pub streams: Vec<ReceiveStream>,
+    pub source_policy: SourcePolicy,
     pub socket: Arc<UdpSocket>,
+    pub stats: Arc<Stats>,
+    pub events: Arc<Events>,
+    /// How long a peer can send nothing before it's considered gone.
+    pub peer_timeout: Duration,
+}
+
+/// One stream being received, and what's kept for it between packets.
+pub struct ReceiveStream {
+    stream_name: AsciiStackString<16>,
+    /// Only accept the stream from this address, if set.
+    from: Option<IpAddr>,
+    audio_out: tokio::sync::mpsc::Sender<Audio>,
+    /// Decrypts packets, if encryption is configured. Unencrypted packets are then rejected.
+    opener: Option<Opener>,
+    /// Decompresses Opus packets. This is created when the first one arrives.
+    opus: Option<OpusDecoder>,
+    /// Puts packets in order, and recovers lost ones if the peer sends parity.
+    fec: FecDecoder,
+    jitter: JitterEstimator,
+    peer: Option<Peer>,
+    /// What the audio engine was last told to expect.
+    playing: Option<AudioFormat>,
+    /// Where this stream's jitter and peer are reported.
+    stream_stats: Arc<StreamStats>,
+}
+
+/// Logs rejected packets without flooding the log.
+struct Rejections {
+    log_limit: RateLimit,
+}
+
+impl Rejections {
+    fn new() -> Self {
+        Self {
+            log_limit: RateLimit::new(Duration::from_secs(10)),
+        }
+    }
+
+    /// Returns true if this one was logged.
+    fn reject(&mut self, counter: &Counter, addr: SocketAddr, reason: impl Display) -> bool {
+        counter.inc();
+        let Some(suppressed) = self.log_limit.check() else {
+            return false;
+        };
+        log::warn!(
+            "Rejected packet from {}: {} ({} in total, {} not logged)",
+            addr,
+            reason,
+            counter.get(),
+            suppressed
+        );
+        true
+    }
+}
+
+/// Who's sending audio, and in what format.
+struct Peer {
+    address: SocketAddr,
+    format: String,
+    last_heard: Instant,
+}
+
+/// Estimates jitter as the average distance of the time between packets from its running mean. Packets carry no
+/// send time, so unlike RFC 3550 this can't tell a sender's uneven pacing from delay on the network.
+#[derive(Default)]
+struct JitterEstimator {
+    last_arrival: Option<Instant>,
+    /// The average time between packets, in seconds.
+    mean_interval: Option<f64>,
+    jitter: f64,
+}
+
+impl JitterEstimator {
+    /// Forgets the last arrival, so a deliberate gap doesn't count as jitter.
+    fn pause(&mut self) {
+        self.last_arrival = None;
+    }
+
+    /// Returns the time since the last packet, if there was one.
+    fn arrived(&mut self, now: Instant) -> Option<f64> {
+        let last = self.last_arrival.replace(now)?;
+        let interval = now.duration_since(last).as_secs_f64();
+        let mean = self.mean_interval.get_or_insert(interval);
+        *mean += (interval - *mean) / 16.0;
+        self.jitter += ((interval - *mean).abs() - self.jitter) / 16.0;
+        Some(interval)
+    }
+}
+
+impl ReceiveStream {
+    pub fn new(
+        stream_name: AsciiStackString<16>,
+        from: Option<IpAddr>,
+        audio_out: tokio::sync::mpsc::Sender<Audio>,
+        opener: Option<Opener>,
+        stats: Arc<Stats>,
+        stream_stats: Arc<StreamStats>,
+    ) -> Self {
+        Self {
+            stream_name,
+            from,
+            audio_out,
+            opener,
+            opus: None,
+            fec: FecDecoder::new(stats),
+            jitter: JitterEstimator::default(),
+            peer: None,
+            playing: None,
+            stream_stats,
+        }
+    }
+
+    fn accepts(&self, stream_name: &AsciiStackString<16>, addr: SocketAddr) -> bool {
+        self.stream_name == *stream_name && self.from.is_none_or(|from| from == addr.ip())
+    }
+
+    /// When the peer will be considered gone, if there is one.
+    fn peer_deadline(&self, peer_timeout: Duration) -> Option<Instant> {
+        self.peer
+            .as_ref()
+            .map(|peer| peer.last_heard + peer_timeout)
+    }
+
+    async fn send(&mut self, stats: &Stats, audio: Audio) -> Result<(), ReceiverError> {
+        self.audio_out
+            .send(audio)
+            .await
+            .map_err(|_| ReceiverError::AudioChannelBroken)?;
+        let queued = self.audio_out.max_capacity() - self.audio_out.capacity();
+        stats.playback_queue.set(queued as f64);
+        Ok(())
+    }
+
+    /// Turns a packet, or the lack of one, into something to play, if there's anything.
+    fn decode_payload(&mut self, received: Received) -> Result<Option<Payload>, PayloadError> {
+        let packet = match received {
+            Received::Packet(packet) => packet,
+            Received::Lost => {
+                return match &mut self.opus {
+                    Some(opus) => Ok(Some(Payload::Samples(opus::FORMAT, opus.decode(None)?))),
+                    None => Ok(None),
+                };
+            }
+        };
+        match packet.header.codec {
+            Codec::PCM => return decode_pcm(&packet).map(Some),
+            Codec::User => {}
+            _ => return Err(PayloadError::UnsupportedFormat(packet.describe_format())),
+        }
+        match UserCodec::try_from(packet.data.first().copied().unwrap_or(0))? {
+            UserCodec::Envelope => Err(PayloadError::Encrypted),
+            UserCodec::Opus => {
+                let opus = match &mut self.opus {
+                    Some(opus) => opus,
+                    None => self.opus.insert(OpusDecoder::new()?),
+                };
+                let pcm = opus.decode(Some(&packet.data))?;
+                Ok(Some(Payload::Samples(opus::FORMAT, pcm)))
+            }
+            // The FEC decoder uses these up, so one here was forged.
+            UserCodec::Parity => Err(PayloadError::UnexpectedParity),
+            UserCodec::Keepalive => Ok(Some(Payload::Idle)),
+        }
+    }
 }
 
 impl Receiver {
-    pub async fn run(self) -> Result<(), ReceiverError> {
+    pub async fn run(mut self) -> Result<(), ReceiverError> {
         let mut buf = [0u8; 1464];
+        let mut rejections = Rejections::new();
+        let mut auth_failures = Rejections::new();
+        let mut decode_failures = Rejections::new();
         loop {
-            let (len, addr) = self.socket.recv_from(&mut buf).await?;
-            if addr.ip() != self.recv_address {
+            // Checked every time, as a steady stream of other packets would keep the timeout from firing.
+            self.forget_silent_peers();
+            let deadline = self
+                .streams
+                .iter()
+                .filter_map(|stream| stream.peer_deadline(self.peer_timeout))
+                .min();
+            let received = match deadline {
+                Some(deadline) => {
+                    match tokio::time::timeout_at(deadline.into(), self.socket.recv_from(&mut buf))
+                        .await
+                    {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    }
+                }
+                None => self.socket.recv_from(&mut buf).await,
+            };
+            let (len, addr) = received?;
+            let arrival = Instant::now();
+            self.stats.packets_received.inc();
+            self.stats.bytes_received.add(len as u64);
+            if !self.source_policy.may_allow(addr) {
+                rejections.reject(&self.stats.rejected, addr, "source is not allowed");
                 continue;
             }
             if len < 4 || &buf[..4] != b"VBAN" {
-                log::warn!("Received obviously invalid packet, discarding");
+                decode_failures.reject(
+                    &self.stats.decode_failures,
+                    addr,
+                    "obviously invalid packet",
+                );
                 continue;
             }
-            let decoded: VbanPacket = match Cursor::new(&mut buf[..len]).read_le() {
+            let mut decoded: VbanPacket = match Cursor::new(&mut buf[..len]).read_le() {
                 Ok(v) => v,
                 Err(e) => {
-                    log::warn!("Failed to decode packet: {}", e);
+                    decode_failures.reject(
+                        &self.stats.decode_failures,
+                        addr,
+                        format_args!("failed to decode packet: {}", e),
+                    );
                     continue;
                 }
             };
-            if decoded.header.stream_name != self.stream_name {
+            let Some(stream) = self
+                .streams
+                .iter_mut()
+                .find(|stream| stream.accepts(&decoded.header.stream_name, addr))
+            else {
                 continue;
+            };
+            if !self.source_policy.allows(addr, &decoded.header.stream_name) {
+                rejections.reject(
+                    &self.stats.rejected,
+                    addr,
+                    format_args!("source may not send stream {}", decoded.header.stream_name),
+                );
+                continue;
+            }
+            if let Some(opener) = &mut stream.opener {
+                match opener.open(&mut decoded.header, &decoded.data) {
+                    Ok(data) => decoded.data = data,
+                    Err(e) => {
+                        // Hooks run as often as this is logged, so a flood doesn't start a flood of commands.
+                        let reason = e.to_string();
+                        if auth_failures.reject(&self.stats.auth_failures, addr, e) {
+                            self.events.emit(Event::AuthFailure {
+                                stream: stream.stream_name.to_string(),
+                                address: addr,
+                                reason,
+                            });
+                        }
+                        continue;
+                    }
+                }
+            }
+            if let Some(current) = &mut stream.peer {
+                if current.address != addr {
+                    // Another sender of the same stream, or a forged one. Stay with the current peer until it goes
+                    // silent, rather than flip between them.
+                    rejections.reject(
+                        &self.stats.rejected,
+                        addr,
+                        format_args!(
+                            "{} is already being sent by {}",
+                            stream.stream_name, current.address
+                        ),
+                    );
+                    continue;
+                }
+                current.last_heard = arrival;
+            }
+            if decoded.user_codec() == Some(UserCodec::Keepalive) {
+                stream.jitter.pause();
+            } else if !is_parity(&decoded) {
+                if let Some(interval) = stream.jitter.arrived(arrival) {
+                    self.stats.packet_interval.observe(interval);
+                    stream.stream_stats.jitter.set(stream.jitter.jitter);
+                }
+                let format = decoded.describe_format();
+                match &mut stream.peer {
+                    Some(current) => {
+                        if current.format != format {
+                            log::info!(
+                                "Peer {} changed format of {} from {} to {}",
+                                addr,
+                                stream.stream_name,
+                                current.format,
+                                format
+                            );
+                            self.events.emit(Event::FormatChanged {
+                                stream: stream.stream_name.to_string(),
+                                address: addr,
+                                previous: std::mem::replace(&mut current.format, format.clone()),
+                                format: format.clone(),
+                            });
+                        }
+                    }
+                    None => {
+                        log::info!(
+                            "Peer {} appeared, sending {} as {}",
+                            addr,
+                            stream.stream_name,
+                            format
+                        );
+                        self.events.emit(Event::PeerAppeared {
+                            stream: stream.stream_name.to_string(),
+                            address: addr,
+                            format: format.clone(),
+                        });
+                        stream.peer = Some(Peer {
+                            address: addr,
+                            format: format.clone(),
+                            last_heard: arrival,
+                        });
+                    }
+                }
+                *stream.stream_stats.peer.lock().unwrap() = Some(PeerInfo {
+                    address: addr,
+                    last_packet: arrival,
+                    format,
+                });
+            }
+            for received in stream.fec.push(decoded) {
+                let audio = match stream.decode_payload(received) {
+                    Ok(Some(Payload::Samples(_, pcm))) if pcm.is_empty() => continue,
+                    Ok(Some(Payload::Samples(format, pcm))) => {
+                        if stream.playing != Some(format) {
+                            stream.playing = Some(format);
+                            stream.send(&self.stats, Audio::Format(format)).await?;
+                        }
+                        Audio::Samples(pcm)
+                    }
+                    Ok(Some(Payload::Idle)) => Audio::Idle,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        decode_failures.reject(&self.stats.decode_failures, addr, e);
+                        continue;
+                    }
+                };
+                stream.send(&self.stats, audio).await?;
             }
-            assert!(matches!(decoded.header.data_type, DataType::I24));
-            assert!(matches!(decoded.header.codec, Codec::PCM));
-            assert!(matches!(decoded.header.sample_rate, SampleRate::Hz48000));
-            assert!(matches!(decoded.header.channels, 1)); // Meaning 2... :)
-            self.audio_out
-                .send(decoded.data)
-                .await
-                .map_err(|_| ReceiverError::AudioChannelBroken)?;
         }
     }
+
+    /// Forgets the peers that have sent nothing for too long.
+    fn forget_silent_peers(&mut self) {
+        let now = Instant::now();
+        for stream in &mut self.streams {
+            if stream
+                .peer_deadline(self.peer_timeout)
+                .is_some_and(|deadline| deadline <= now)
+            {
+                let address = stream.peer.take().expect("has a deadline").address;
+                log::info!("Peer {} went silent on {}", address, stream.stream_name);
+                self.events.emit(Event::PeerSilent {
+                    stream: stream.stream_name.to_string(),
+                    address,
+                });
+            }
+        }
+    }
+}
+
+/// Converts uncompressed audio to 24-bit samples in whole frames, if it's in a format that can be played.
+fn decode_pcm(packet: &VbanPacket) -> Result<Payload, PayloadError> {
+    let header = &packet.header;
+    let unsupported = || PayloadError::UnsupportedFormat(packet.describe_format());
+    let format = AudioFormat {
+        rate: header
+            .sample_rate
+            .get_rate_if_known()
+            .ok_or_else(unsupported)?,
+        channels: header.channels.checked_add(1).ok_or_else(unsupported)?,
+    };
+    if !format.spec().is_valid() {
+        return Err(unsupported());
+    }
+    let mut pcm = to_s24le(&packet.data, header.data_type).ok_or_else(unsupported)?;
+    let frame_size = usize::from(format.channels) * 3;
+    pcm.truncate(pcm.len() / frame_size * frame_size);
+    Ok(Payload::Samples(format, pcm))
 }
@@ -1,69 +1,258 @@
+use std::fmt::{Display, Formatter};
 use std::io::Cursor;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use binrw::BinWriterExt;
 use thiserror::Error;
 use tokio::net::UdpSocket;
 
 use crate::asciistackstr::AsciiStackString;
-use crate::vban::packet::{Codec, DataType, SampleRate, SubProtocol, VbanHeader, VbanPacket};
+use crate::audio_engine::{Audio, AudioFormat};
+use crate::config::global::FormatConfig;
+use crate::pcm::from_s24le;
+use crate::stats::Stats;
+use crate::vban::crypto::Sealer;
+use crate::vban::fec::ParityEncoder;
+use crate::vban::opus::{OpusEncoder, OpusError};
+use crate::vban::packet::{
+    Codec, DataType, SampleRate, SubProtocol, UserCodec, VbanHeader, VbanPacket, VbanPacketError,
+};
 
 #[derive(Debug, Error)]
 pub enum TransmitterError {
     #[error("Socket write error: {0}")]
     SocketWrite(#[from] std::io::Error),
+    #[error("Couldn't compress audio: {0}")]
+    Opus(#[from] OpusError),
+}
+
+#[derive(Debug, Error)]
+pub enum FormatError {
+    #[error("{0}")]
+    Packet(#[from] VbanPacketError),
+    #[error("Can't send {0}")]
+    Invalid(AudioFormat),
+    #[error("Packets can be at most {max_ms:.2} ms long in this format, not {packet_ms} ms")]
+    PacketTooLong { packet_ms: f32, max_ms: f32 },
+    #[error("Packets must be at least one sample long, not {0} ms")]
+    PacketTooShort(f32),
 }
 
 pub struct Transmitter {
     pub stream_name: AsciiStackString<16>,
     pub dest_address: SocketAddr,
-    pub audio_in: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    pub audio_in: tokio::sync::mpsc::Receiver<Audio>,
     pub socket: Arc<UdpSocket>,
+    /// Encrypts packets, if encryption is configured.
+    pub sealer: Option<Sealer>,
+    /// Compresses audio, if Opus is configured.
+    pub opus: Option<OpusEncoder>,
+    /// Sends parity packets, if FEC is configured.
+    pub fec: Option<ParityEncoder>,
+    /// How often to send a keepalive while the noise gate is shut.
+    pub keepalive_interval: Duration,
+    pub format: TxFormat,
+    pub stats: Arc<Stats>,
 }
 
-const SAMPLE_SIZE: u32 = 2 * 3;
-const MAX_DATA_PACKET_SIZE: u32 = 1436;
+pub const MAX_DATA_PACKET_SIZE: u32 = 1436;
+/// The most samples per channel VBAN can put in a packet.
+const MAX_SAMPLES_PER_PACKET: u32 = 256;
 
-const fn samples_per_packet() -> u32 {
-    let samples_per_packet = MAX_DATA_PACKET_SIZE / SAMPLE_SIZE;
-    if samples_per_packet <= 256 {
-        samples_per_packet
-    } else {
-        256
+/// How outgoing audio is sent.
+#[derive(Debug, Clone, Copy)]
+pub struct TxFormat {
+    /// The format audio is captured and processed in.
+    pub audio: AudioFormat,
+    sample_rate: SampleRate,
+    data_type: DataType,
+    /// Samples per channel in each packet.
+    samples_per_packet: u32,
+}
+
+impl TxFormat {
+    /// Works out how much audio to put in each packet, leaving room for `overhead` extra bytes.
+    pub fn new(config: &FormatConfig, overhead: u32) -> Result<Self, FormatError> {
+        let audio = AudioFormat {
+            rate: config.sample_rate,
+            channels: config.channels,
+        };
+        let sample_rate = SampleRate::try_from(audio.rate)?;
+        if !audio.spec().is_valid() {
+            return Err(FormatError::Invalid(audio));
+        }
+        let data_type = DataType::from(config.sample_type);
+        let frame_size = data_type
+            .sample_size()
+            .expect("configurable sample types have a whole number of bytes")
+            as u32
+            * u32::from(audio.channels);
+        let max_samples =
+            ((MAX_DATA_PACKET_SIZE - overhead) / frame_size).min(MAX_SAMPLES_PER_PACKET);
+        let samples_per_packet = match config.packet_ms {
+            Some(packet_ms) => {
+                let samples = (audio.rate as f32 * packet_ms / 1000.0).round() as u32;
+                if samples > max_samples {
+                    return Err(FormatError::PacketTooLong {
+                        packet_ms,
+                        max_ms: max_samples as f32 * 1000.0 / audio.rate as f32,
+                    });
+                }
+                if samples == 0 {
+                    return Err(FormatError::PacketTooShort(packet_ms));
+                }
+                samples
+            }
+            None => max_samples,
+        };
+        Ok(Self {
+            audio,
+            sample_rate,
+            data_type,
+            samples_per_packet,
+        })
+    }
+
+    /// How many bytes of captured 24-bit audio go in each packet.
+    pub fn capture_size(&self) -> u32 {
+        self.samples_per_packet * u32::from(self.audio.channels) * 3
     }
 }
 
-const SAMPLES_PER_PACKET: u32 = samples_per_packet();
-pub const USABLE_DATA_PACKET_SIZE: u32 = SAMPLES_PER_PACKET * SAMPLE_SIZE;
+impl Display for TxFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, {:?}", self.audio, self.data_type)
+    }
+}
 
 impl Transmitter {
     pub async fn run(mut self) -> Result<(), TransmitterError> {
         let mut header = VbanHeader {
-            sample_rate: SampleRate::Hz48000,
+            sample_rate: self.format.sample_rate,
             sub_protocol: SubProtocol::Audio,
-            samples_per_frame: (SAMPLES_PER_PACKET - 1) as u8,
-            channels: 1, // meaning 2 ...
-            data_type: DataType::I24,
+            samples_per_frame: 0,
+            // VBAN counts from 1.
+            channels: self.format.audio.channels - 1,
+            data_type: self.format.data_type,
             codec: Codec::PCM,
-            stream_name: self.stream_name,
+            stream_name: self.stream_name.clone(),
             frame_counter: 0,
         };
         let mut buf = Vec::new();
-        while let Some(audio_packet) = self.audio_in.recv().await {
-            let packet = VbanPacket {
-                header: header.clone(),
-                data: audio_packet,
+        let mut idle = false;
+        loop {
+            let audio = if idle {
+                match tokio::time::timeout(self.keepalive_interval, self.audio_in.recv()).await {
+                    Ok(audio) => audio,
+                    Err(_) => {
+                        self.send_keepalive(&mut buf, &mut header).await?;
+                        continue;
+                    }
+                }
+            } else {
+                self.audio_in.recv().await
+            };
+            let audio_packet = match audio {
+                Some(Audio::Samples(audio_packet)) => audio_packet,
+                Some(Audio::Idle) => {
+                    idle = true;
+                    // Tell the peer straight away, so it doesn't take the silence for loss.
+                    self.send_keepalive(&mut buf, &mut header).await?;
+                    continue;
+                }
+                // Captured audio is always in the format we send.
+                Some(Audio::Format(_)) => continue,
+                None => break,
             };
-            buf.clear();
-            Cursor::new(&mut buf)
-                .write_le(&packet)
-                .expect("should always be able to write to a Vec");
-            let sent = self.socket.send_to(&buf, self.dest_address).await?;
-            assert_eq!(sent, buf.len(), "should always send the whole packet");
-            header = header.next();
+            idle = false;
+            let (codec, samples, payloads) = match &mut self.opus {
+                Some(opus) => (
+                    Codec::User,
+                    opus.samples_per_frame() as u32,
+                    opus.encode(&audio_packet)?,
+                ),
+                None => (
+                    Codec::PCM,
+                    audio_packet.len() as u32 / (u32::from(self.format.audio.channels) * 3),
+                    vec![from_s24le(&audio_packet, self.format.data_type)
+                        .expect("configurable sample types can be converted")],
+                ),
+            };
+            for payload in payloads {
+                let packet_header = VbanHeader {
+                    // Opus frames can be longer than VBAN allows, but the receiver doesn't rely on this.
+                    samples_per_frame: (samples.min(256) - 1) as u8,
+                    codec,
+                    ..header.clone()
+                };
+                self.send_payload(&mut buf, &mut header, packet_header, payload)
+                    .await?;
+            }
         }
 
         Ok(())
     }
+
+    async fn send_keepalive(
+        &mut self,
+        buf: &mut Vec<u8>,
+        header: &mut VbanHeader,
+    ) -> Result<(), TransmitterError> {
+        let keepalive_header = VbanHeader {
+            samples_per_frame: 0,
+            codec: Codec::User,
+            ..header.clone()
+        };
+        let payload = vec![u8::from(UserCodec::Keepalive)];
+        self.send_payload(buf, header, keepalive_header, payload)
+            .await
+    }
+
+    /// Sends a payload, and its parity packet if that completes a group. `header` is moved past both.
+    async fn send_payload(
+        &mut self,
+        buf: &mut Vec<u8>,
+        header: &mut VbanHeader,
+        packet_header: VbanHeader,
+        payload: Vec<u8>,
+    ) -> Result<(), TransmitterError> {
+        let parity = self
+            .fec
+            .as_mut()
+            .and_then(|fec| fec.add(&packet_header, &payload));
+        self.send(buf, packet_header, payload).await?;
+        *header = header.clone().next();
+        if let Some(parity) = parity {
+            let parity_header = VbanHeader {
+                codec: Codec::User,
+                ..header.clone()
+            };
+            self.send(buf, parity_header, parity).await?;
+            *header = header.clone().next();
+        }
+        Ok(())
+    }
+
+    async fn send(
+        &mut self,
+        buf: &mut Vec<u8>,
+        header: VbanHeader,
+        data: Vec<u8>,
+    ) -> Result<(), TransmitterError> {
+        let mut packet = VbanPacket { header, data };
+        if let Some(sealer) = &mut self.sealer {
+            packet.data = sealer.seal(&mut packet.header, &packet.data);
+        }
+        buf.clear();
+        Cursor::new(&mut *buf)
+            .write_le(&packet)
+            .expect("should always be able to write to a Vec");
+        let sent = self.socket.send_to(buf, self.dest_address).await?;
+        assert_eq!(sent, buf.len(), "should always send the whole packet");
+        self.stats.packets_sent.inc();
+        self.stats.bytes_sent.add(sent as u64);
+        Ok(())
+    }
 }
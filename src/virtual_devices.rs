@@ -0,0 +1,296 @@
+//! A sink and source in PulseAudio for the link, so other applications can use it as a speaker and microphone.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use libpulse_binding::callbacks::ListResult;
+use libpulse_binding::context::{Context, FlagSet, State};
+use libpulse_binding::error::PAErr;
+use libpulse_binding::mainloop::standard::{IterateResult, Mainloop};
+use libpulse_binding::operation::{Operation, State as OperationState};
+use thiserror::Error;
+
+use crate::config::global::PulseConfig;
+
+#[derive(Debug, Error)]
+pub enum VirtualDeviceError {
+    #[error("Couldn't connect to PulseAudio: {0}")]
+    Connect(PAErr),
+    #[error("Lost the connection to PulseAudio")]
+    Disconnected,
+    #[error("Couldn't load {0}")]
+    Load(&'static str),
+}
+
+/// The devices for one stream. They're unloaded when this is dropped.
+pub struct VirtualDevices {
+    server: Option<String>,
+    application_name: String,
+    names: DeviceNames,
+    /// The index and argument of each loaded module, in the order they were loaded.
+    modules: Vec<(u32, String)>,
+}
+
+#[derive(PartialEq, Eq)]
+struct DeviceNames {
+    description: String,
+    /// Applications play into this, and we capture from its monitor.
+    send: String,
+    /// We play into this, and the source follows its monitor.
+    receive: String,
+    /// Applications record from this.
+    source: String,
+}
+
+impl DeviceNames {
+    fn new(stream_name: &str) -> Self {
+        // Device names can only have some characters, and descriptions are quoted.
+        let name: String = stream_name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c.to_ascii_lowercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let description = stream_name.replace(['"', '\'', '\\'], "");
+        Self {
+            description: format!("VBAN: {}", description),
+            send: format!("vban_{}", name),
+            receive: format!("vban_{}_received", name),
+            source: format!("vban_{}_mic", name),
+        }
+    }
+}
+
+impl VirtualDevices {
+    /// Makes sure `current` holds the devices for this stream and they're still loaded, replacing or unloading any that
+    /// don't match.
+    pub fn ensure(
+        current: &mut Option<VirtualDevices>,
+        pulse: &PulseConfig,
+        stream_name: &str,
+    ) -> Result<(), VirtualDeviceError> {
+        let names = DeviceNames::new(stream_name);
+        let matches = current.as_ref().is_some_and(|devices| {
+            devices.server == pulse.server
+                && devices.application_name == pulse.application_name
+                && devices.names == names
+        });
+        let loaded = match current {
+            Some(devices) if pulse.virtual_devices && matches => {
+                tokio::task::block_in_place(|| devices.is_loaded())?
+            }
+            _ => false,
+        };
+        if matches && pulse.virtual_devices && !loaded {
+            log::warn!(
+                "Virtual devices are gone, PulseAudio probably restarted. Creating them again"
+            );
+        }
+        if !loaded {
+            // Unload first, as the new ones may have the same names.
+            *current = None;
+        }
+        if pulse.virtual_devices && current.is_none() {
+            *current = Some(tokio::task::block_in_place(|| Self::load(pulse, names))?);
+        }
+        Ok(())
+    }
+
+    fn load(pulse: &PulseConfig, names: DeviceNames) -> Result<Self, VirtualDeviceError> {
+        let mut connection = Connection::open(pulse.server.as_deref(), &pulse.application_name)?;
+        // A previous run that didn't exit cleanly can leave these behind, and the names would clash.
+        for (index, argument) in connection.modules()? {
+            let ours = argument.split_whitespace().any(|arg| {
+                [&names.send, &names.receive, &names.source]
+                    .iter()
+                    .any(|name| arg.ends_with(&format!("_name={}", name)))
+            });
+            if ours {
+                log::info!("Unloading virtual device left behind: {}", argument);
+                connection.unload_module(index)?;
+            }
+        }
+        let mut devices = Self {
+            server: pulse.server.clone(),
+            application_name: pulse.application_name.clone(),
+            names,
+            modules: Vec::new(),
+        };
+        let names = &devices.names;
+        let modules = [
+            (
+                "module-null-sink",
+                format!(
+                    "sink_name={} sink_properties='device.description=\"{}\"'",
+                    names.send, names.description
+                ),
+            ),
+            (
+                "module-null-sink",
+                format!(
+                    "sink_name={} sink_properties='device.description=\"{} (received)\"'",
+                    names.receive, names.description
+                ),
+            ),
+            (
+                "module-remap-source",
+                format!(
+                    "source_name={} master={}.monitor source_properties='device.description=\"{}\"'",
+                    names.source, names.receive, names.description
+                ),
+            ),
+        ];
+        for (module, argument) in modules {
+            // Anything already loaded is unloaded when `devices` is dropped.
+            let index = connection.load_module(module, &argument)?;
+            devices.modules.push((index, argument));
+        }
+        log::info!(
+            "Created virtual devices {} and {} for \"{}\"",
+            devices.names.send,
+            devices.names.source,
+            devices.names.description
+        );
+        Ok(devices)
+    }
+
+    /// Where to capture what applications play to the link.
+    pub fn capture_device(&self) -> String {
+        format!("{}.monitor", self.names.send)
+    }
+
+    /// Where to play what the link receives.
+    pub fn playback_device(&self) -> String {
+        self.names.receive.clone()
+    }
+
+    /// Whether all the modules are still there. They're gone if PulseAudio restarted.
+    fn is_loaded(&self) -> Result<bool, VirtualDeviceError> {
+        let mut connection = Connection::open(self.server.as_deref(), &self.application_name)?;
+        let loaded = connection.modules()?;
+        Ok(self.modules.iter().all(|module| loaded.contains(module)))
+    }
+
+    fn unload(&mut self) -> Result<(), VirtualDeviceError> {
+        let mut connection = Connection::open(self.server.as_deref(), &self.application_name)?;
+        let loaded = connection.modules()?;
+        // Unload in reverse, so nothing is left depending on what's gone.
+        while let Some(module) = self.modules.pop() {
+            // After PulseAudio restarts, the index could belong to something else.
+            if loaded.contains(&module) {
+                connection.unload_module(module.0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VirtualDevices {
+    fn drop(&mut self) {
+        if self.modules.is_empty() {
+            return;
+        }
+        match tokio::task::block_in_place(|| self.unload()) {
+            Ok(()) => log::info!("Removed virtual devices for \"{}\"", self.names.description),
+            Err(e) => log::warn!("Couldn't remove virtual devices: {}", e),
+        }
+    }
+}
+
+/// A short-lived connection to PulseAudio, for making requests one at a time.
+struct Connection {
+    mainloop: Mainloop,
+    context: Context,
+}
+
+impl Connection {
+    fn open(server: Option<&str>, application_name: &str) -> Result<Self, VirtualDeviceError> {
+        let mut mainloop = Mainloop::new().ok_or(VirtualDeviceError::Disconnected)?;
+        let mut context =
+            Context::new(&mainloop, application_name).ok_or(VirtualDeviceError::Disconnected)?;
+        context
+            .connect(server, FlagSet::NOFLAGS, None)
+            .map_err(VirtualDeviceError::Connect)?;
+        loop {
+            iterate(&mut mainloop)?;
+            match context.get_state() {
+                State::Ready => break,
+                State::Failed | State::Terminated => {
+                    return Err(VirtualDeviceError::Connect(context.errno()));
+                }
+                _ => {}
+            }
+        }
+        Ok(Self { mainloop, context })
+    }
+
+    fn wait<T: ?Sized>(&mut self, operation: Operation<T>) -> Result<(), VirtualDeviceError> {
+        while operation.get_state() == OperationState::Running {
+            iterate(&mut self.mainloop)?;
+        }
+        match operation.get_state() {
+            OperationState::Done => Ok(()),
+            _ => Err(VirtualDeviceError::Disconnected),
+        }
+    }
+
+    /// Lists the index and argument of every loaded module.
+    fn modules(&mut self) -> Result<Vec<(u32, String)>, VirtualDeviceError> {
+        let modules = Rc::new(RefCell::new(Vec::new()));
+        let found = Rc::clone(&modules);
+        let operation = self
+            .context
+            .introspect()
+            .get_module_info_list(move |result| {
+                if let ListResult::Item(module) = result {
+                    let argument = module.argument.as_deref().unwrap_or_default();
+                    found
+                        .borrow_mut()
+                        .push((module.index, argument.to_string()));
+                }
+            });
+        self.wait(operation)?;
+        Ok(modules.take())
+    }
+
+    fn load_module(
+        &mut self,
+        module: &'static str,
+        argument: &str,
+    ) -> Result<u32, VirtualDeviceError> {
+        let loaded = Rc::new(RefCell::new(None));
+        let result = Rc::clone(&loaded);
+        let operation = self
+            .context
+            .introspect()
+            .load_module(module, argument, move |index| {
+                *result.borrow_mut() = Some(index);
+            });
+        self.wait(operation)?;
+        // PulseAudio reports failure as an invalid index.
+        let index = loaded.take().filter(|&index| index != u32::MAX);
+        index.ok_or(VirtualDeviceError::Load(module))
+    }
+
+    fn unload_module(&mut self, index: u32) -> Result<(), VirtualDeviceError> {
+        let operation = self.context.introspect().unload_module(index, |_| {});
+        self.wait(operation)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.context.disconnect();
+    }
+}
+
+fn iterate(mainloop: &mut Mainloop) -> Result<(), VirtualDeviceError> {
+    match mainloop.iterate(true) {
+        IterateResult::Success(_) => Ok(()),
+        IterateResult::Quit(_) | IterateResult::Err(_) => Err(VirtualDeviceError::Disconnected),
+    }
+}